@@ -1,8 +1,9 @@
 use std::io::Result;
 
-use prost_build::compile_protos;
-
 fn main() -> Result<()> {
-    compile_protos(&["src/patch.proto"], &["src/"])?;
+    // the server keeps patches and decisions on disk as JSON
+    prost_build::Config::new()
+        .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
+        .compile_protos(&["src/patch.proto"], &["src/"])?;
     Ok(())
 }
@@ -1,11 +1,13 @@
 use std::path::PathBuf;
 
+use chrono::NaiveDate;
 use clap::{Args, Parser, Subcommand};
 use regex::Regex;
 
+use crate::audit::Outcome;
+
 #[derive(Parser, Debug)]
 #[command(version, author, about)]
-// TODO: support configuring port / url
 pub struct Cli {
     /// enable additional log information
     #[arg(short, long, action = clap::ArgAction::Count, global = true)]
@@ -28,7 +30,73 @@ pub enum Command {
     Client(ClientMode),
 
     /// run the patchpal server
-    Server,
+    Server(ServerArgs),
+
+    /// search the server's audit log of decisions
+    Log(LogArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ServerArgs {
+    /// address to accept client connections on
+    #[arg(short, long, value_name = "ADDR", default_value = "127.0.0.1:8443")]
+    pub listen: String,
+
+    /// serve over TLS (wss://) with this PEM certificate chain
+    #[arg(long, value_name = "PEM", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// private key (PEM) for --tls-cert
+    #[arg(long, value_name = "PEM", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// only accept clients presenting one of these tokens (one per line)
+    #[arg(long, value_name = "FILE")]
+    pub tokens: Option<PathBuf>,
+
+    /// TOML file of named client tokens and allow/deny rules for clients and repos
+    #[arg(short, long, value_name = "FILE", conflicts_with = "tokens")]
+    pub config: Option<PathBuf>,
+
+    /// file to keep the review queue and undelivered decisions in, so they survive
+    /// a restart
+    #[arg(long, value_name = "FILE", default_value = "patchpal-state.jsonl")]
+    pub state: PathBuf,
+
+    /// file to append a record of every decision to
+    #[arg(long, value_name = "FILE", default_value = "patchpal-audit.jsonl")]
+    pub audit_log: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct LogArgs {
+    /// the server's audit log
+    #[arg(long, value_name = "FILE", default_value = "patchpal-audit.jsonl")]
+    pub audit_log: PathBuf,
+
+    /// only decisions on repos matching this pattern (`*` globs)
+    #[arg(short, long, value_name = "PATTERN")]
+    pub repo: Option<String>,
+
+    /// only decisions on patches from clients matching this pattern (`*` globs)
+    #[arg(short, long, value_name = "PATTERN")]
+    pub client: Option<String>,
+
+    /// only decisions with this overall outcome
+    #[arg(short, long)]
+    pub status: Option<Outcome>,
+
+    /// only decisions made on or after this day (YYYY-MM-DD, UTC)
+    #[arg(long, value_name = "DATE")]
+    pub since: Option<NaiveDate>,
+
+    /// only decisions made on or before this day (YYYY-MM-DD, UTC)
+    #[arg(long, value_name = "DATE")]
+    pub until: Option<NaiveDate>,
+
+    /// print the matching entries as JSON lines
+    #[arg(long)]
+    pub json: bool,
 }
 
 #[derive(Args, Debug)]
@@ -44,6 +112,24 @@ pub struct ClientMode {
 
     /// enable additional log information
     pub metadata: Option<String>,
+
+    /// server to submit the patch to (e.g. `ws://reviewer:8443`, or just `host:port`)
+    #[arg(short, long, value_name = "URL", env = "PATCHPAL_SERVER")]
+    pub server: Option<String>,
+
+    /// trust this CA (PEM) instead of the system roots when connecting with wss://,
+    /// e.g. a server's self-signed certificate
+    #[arg(long, value_name = "PEM", env = "PATCHPAL_CA_CERT")]
+    pub ca_cert: Option<PathBuf>,
+
+    /// token to authenticate with, if the server requires one
+    #[arg(long, env = "PATCHPAL_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// how many times in a row to try reconnecting if the server goes away
+    /// during review
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub retries: u32,
 }
 
 impl Default for ClientMode {
@@ -52,6 +138,11 @@ impl Default for ClientMode {
             local: Some(LocalClientArgs::default()),
             github: None,
             metadata: None,
+            // clap only reads the env var when the client subcommand is given
+            server: std::env::var("PATCHPAL_SERVER").ok(),
+            ca_cert: std::env::var_os("PATCHPAL_CA_CERT").map(PathBuf::from),
+            token: std::env::var("PATCHPAL_TOKEN").ok(),
+            retries: 10,
         }
     }
 }
@@ -63,6 +154,31 @@ pub struct LocalClientArgs {
     // this 'C' short flag matches git's behavior for changing git repo path
     #[arg(short = 'C', long, required = false)]
     pub path: Option<PathBuf>,
+
+    /// review staged changes (HEAD to index) instead of unstaged ones
+    #[arg(long, conflicts_with = "rev", required = false)]
+    pub staged: bool,
+
+    /// review a commit or a range of commits (`base..head`) instead of the working tree
+    #[arg(long, value_name = "REVSPEC", required = false)]
+    pub rev: Option<String>,
+
+    /// leave untracked files out of the working tree's patch
+    #[arg(long, conflicts_with_all = ["staged", "rev"], required = false)]
+    pub no_untracked: bool,
+
+    /// commit the accepted changes after staging them
+    #[arg(long, conflicts_with = "rev", required = false)]
+    pub commit: bool,
+
+    /// template for the commit message: supports {metadata}, {repo}, {files} and
+    /// {message} (the reviewer's edits)
+    #[arg(long, requires = "commit", required = false)]
+    pub commit_template: Option<String>,
+
+    /// revert rejected hunks in the working tree, keeping only what was accepted
+    #[arg(long, conflicts_with_all = ["staged", "rev"], required = false)]
+    pub discard_rejected: bool,
 }
 
 #[derive(Args, Debug)]
@@ -106,11 +222,35 @@ mod tests {
         parses!("patchpal -vvv");
         parses!("patchpal server -v");
         parses!("patchpal server");
+        parses!("patchpal server --listen 0.0.0.0:9000");
+        parses!("patchpal server -l [::1]:8443");
+        parses!("patchpal server --tls-cert cert.pem --tls-key key.pem");
+        parses!("patchpal server --tokens tokens.txt");
+        parses!("patchpal server --config patchpal.toml --listen 0.0.0.0:9000");
+        parses!("patchpal server --state /var/lib/patchpal/state.jsonl");
+        parses!("patchpal server --audit-log /var/log/patchpal.jsonl");
+        parses!("patchpal log");
+        parses!("patchpal log --repo payments-* --status rejected");
+        parses!("patchpal log --since 2025-03-01 --until 2025-03-31 --json");
+        parses!("patchpal log -c codemod-bot --audit-log audit.jsonl");
         parses!("patchpal client");
         parses!("patchpal client METADATA");
         parses!("patchpal client --path ../bar");
+        parses!("patchpal client --commit");
+        parses!("patchpal client --commit --commit-template {metadata}");
+        parses!("patchpal client --path ../bar --commit METADATA");
+        parses!("patchpal client --discard-rejected --commit");
+        parses!("patchpal client --staged --commit");
+        parses!("patchpal client --rev HEAD~3");
+        parses!("patchpal client --path ../bar --rev main..feature METADATA");
+        parses!("patchpal client --no-untracked --discard-rejected");
         parses!("patchpal client --repo foo/bar --pr-number 123");
         parses!("patchpal client --repo foo/bar --pr-number 123 METADATA");
+        parses!("patchpal client --server ws://reviewer:9000");
+        parses!("patchpal client -s reviewer:9000 --repo foo/bar --pr-number 123");
+        parses!("patchpal client --server wss://reviewer:9000 --ca-cert ca.pem");
+        parses!("patchpal client --token s3cret");
+        parses!("patchpal client --retries 0");
         // ideally we could intuit the repo, but not yet:
         // parses!("patchpal client --pr-number 123");
     }
@@ -124,8 +264,27 @@ mod tests {
         }
         fails!("patchpal server client");
         fails!("patchpal server --path ../bar");
+        fails!("patchpal server --server ws://reviewer:9000");
+        fails!("patchpal client --listen 0.0.0.0:9000");
+        fails!("patchpal server --tls-cert cert.pem");
+        fails!("patchpal server --tls-key key.pem");
+        fails!("patchpal server --token s3cret");
+        fails!("patchpal server --retries 3");
+        fails!("patchpal client --state state.jsonl");
+        fails!("patchpal log --status maybe");
+        fails!("patchpal log --since yesterday");
+        fails!("patchpal log --listen 0.0.0.0:9000");
+        fails!("patchpal server --config patchpal.toml --tokens tokens.txt");
         fails!("patchpal client --path ../bar --repo foo/bar");
         fails!("patchpal client --path ../bar --repo foo/bar --pr-number 123");
+        fails!("patchpal client --commit-template {metadata}");
+        fails!("patchpal client --commit --repo foo/bar --pr-number 123");
+        fails!("patchpal client --discard-rejected --repo foo/bar --pr-number 123");
+        fails!("patchpal client --staged --rev HEAD");
+        fails!("patchpal client --rev HEAD --commit");
+        fails!("patchpal client --staged --discard-rejected");
+        fails!("patchpal client --staged --no-untracked");
+        fails!("patchpal client --rev HEAD --repo foo/bar --pr-number 123");
         // ideally we could intuit the repo, but not yet:
         fails!("patchpal client --pr-number 123");
     }
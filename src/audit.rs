@@ -0,0 +1,392 @@
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use glob::Pattern;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    args::LogArgs,
+    models::{patch_response::Status, PatchResponse},
+    review::Review,
+    store::Received,
+};
+
+/// a line of the audit log: who sent what, and what the reviewer made of it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// when the reviewer decided
+    pub at: DateTime<Utc>,
+    pub received_at: DateTime<Utc>,
+    /// the patch's ID in the server's state file
+    pub id: String,
+    /// address the patch was sent from
+    pub addr: Option<String>,
+    /// name of the authenticated client that sent it
+    pub client: Option<String>,
+    pub metadata: Option<String>,
+    pub repo: Option<String>,
+    pub files: Vec<String>,
+    pub status: Outcome,
+    pub decisions: Vec<Decision>,
+    /// SHA-256 of the submitted patch's text
+    pub patch_sha256: String,
+    /// SHA-256 of what was accepted, which differs from the submitted hunks if the
+    /// reviewer changed them
+    #[serde(default)]
+    pub accepted_sha256: String,
+}
+
+/// the reviewer's decision on a hunk, or a file-level change when `hunk` is `None`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Decision {
+    pub file: String,
+    /// which of the file's hunks in the submitted patch, shared by the parts of a split one
+    pub hunk: Option<u32>,
+    pub status: Outcome,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub changes: Vec<Change>,
+}
+
+/// something the reviewer did to a hunk before deciding on it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Change {
+    Split,
+    Edited,
+    LinesSelected,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Accepted,
+    Rejected,
+    Partial,
+    Unknown,
+}
+
+impl From<Status> for Outcome {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Accepted => Outcome::Accepted,
+            Status::Rejected => Outcome::Rejected,
+            Status::Partial => Outcome::Partial,
+            Status::Unknown => Outcome::Unknown,
+        }
+    }
+}
+
+impl Entry {
+    pub fn new(received: &Received, response: &PatchResponse) -> Self {
+        let patch = &received.patch;
+        // indexed the same way as the response's decisions
+        let files: Vec<String> = match Review::new(&patch.patch, &patch.files) {
+            Ok(review) => review
+                .files()
+                .iter()
+                .map(|f| f.path().to_string())
+                .collect(),
+            Err(_) => vec![],
+        };
+        let file = |idx: u32| files.get(idx as usize).cloned().unwrap_or_default();
+        let files_decided = response.files.iter().map(|f| Decision {
+            file: file(f.file),
+            hunk: None,
+            status: f.status().into(),
+            changes: vec![],
+        });
+        let hunks_decided = response.hunks.iter().map(|h| {
+            let changes = [
+                (h.split, Change::Split),
+                (h.edited, Change::Edited),
+                (h.lines_selected, Change::LinesSelected),
+            ];
+            Decision {
+                file: file(h.file),
+                hunk: Some(h.original),
+                status: h.status().into(),
+                changes: changes
+                    .into_iter()
+                    .filter_map(|(made, change)| made.then_some(change))
+                    .collect(),
+            }
+        });
+        let decisions = files_decided.chain(hunks_decided).collect();
+
+        Entry {
+            at: Utc::now(),
+            received_at: received.at,
+            id: received.id.clone(),
+            addr: received.addr.clone(),
+            client: received.client.clone(),
+            metadata: patch.metadata.clone(),
+            repo: patch.repo.clone(),
+            status: response.status().into(),
+            decisions,
+            patch_sha256: format!("{:x}", Sha256::digest(&patch.patch)),
+            accepted_sha256: format!("{:x}", Sha256::digest(&response.patch)),
+            files,
+        }
+    }
+}
+
+/// the server's append-only record of every decision
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("couldn't open the audit log at {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => bail!(
+                "another server is writing to {}, give each one its own --state and --audit-log",
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("couldn't lock {}", path.display()))
+            }
+        }
+        Ok(AuditLog {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &Entry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        let mut file = self.file.lock().expect("audit log lock poisoned");
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// `patchpal log`: prints the audit log's entries that match every filter given
+pub struct Query {
+    path: PathBuf,
+    repo: Option<Pattern>,
+    client: Option<Pattern>,
+    status: Option<Outcome>,
+    since: Option<NaiveDate>,
+    until: Option<NaiveDate>,
+    json: bool,
+}
+
+impl TryFrom<LogArgs> for Query {
+    type Error = anyhow::Error;
+
+    fn try_from(args: LogArgs) -> anyhow::Result<Self> {
+        let pattern = |glob: Option<String>| {
+            glob.map(|glob| Pattern::new(&glob).with_context(|| format!("invalid pattern {glob}")))
+                .transpose()
+        };
+        Ok(Query {
+            path: args.audit_log,
+            repo: pattern(args.repo)?,
+            client: pattern(args.client)?,
+            status: args.status,
+            since: args.since,
+            until: args.until,
+            json: args.json,
+        })
+    }
+}
+
+impl Query {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let file = File::open(&self.path)
+            .with_context(|| format!("couldn't read the audit log at {}", self.path.display()))?;
+        let mut stdout = std::io::stdout().lock();
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(
+                        "Skipping line {} of {}: {}",
+                        idx + 1,
+                        self.path.display(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            if !self.matches(&entry) {
+                continue;
+            }
+            if self.json {
+                writeln!(stdout, "{line}")?;
+            } else {
+                writeln!(stdout, "{}", summary(&entry))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let glob = |pattern: &Option<Pattern>, value: &Option<String>| match pattern {
+            Some(pattern) => value.as_deref().is_some_and(|v| pattern.matches(v)),
+            None => true,
+        };
+        let date = entry.at.date_naive();
+        glob(&self.repo, &entry.repo)
+            && glob(&self.client, &entry.client)
+            && self.status.is_none_or(|status| status == entry.status)
+            && self.since.is_none_or(|since| date >= since)
+            && self.until.is_none_or(|until| date <= until)
+    }
+}
+
+/// e.g. "2025-03-20 14:02  partial   patchpal  alice@10.0.0.5  2 file(s), 3/4 accepted  bump deps"
+fn summary(entry: &Entry) -> String {
+    let accepted = entry
+        .decisions
+        .iter()
+        .filter(|d| d.status == Outcome::Accepted)
+        .count();
+    let from = match (&entry.client, &entry.addr) {
+        (Some(client), Some(addr)) => format!("{client}@{addr}"),
+        (Some(client), None) => client.clone(),
+        (None, Some(addr)) => addr.clone(),
+        (None, None) => "unknown".to_string(),
+    };
+    format!(
+        "{}  {:<8}  {}  {}  {} file(s), {}/{} accepted  {}",
+        entry.at.format("%Y-%m-%d %H:%M"),
+        format!("{:?}", entry.status).to_lowercase(),
+        entry.repo.as_deref().unwrap_or("unknown repo"),
+        from,
+        entry.files.len(),
+        accepted,
+        entry.decisions.len(),
+        entry.metadata.as_deref().unwrap_or_default(),
+    )
+    .trim_end()
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(at: &str, repo: &str, status: Outcome) -> Entry {
+        Entry {
+            at: at.parse().unwrap(),
+            received_at: at.parse().unwrap(),
+            id: String::new(),
+            addr: None,
+            client: Some("codemod-bot".into()),
+            metadata: None,
+            repo: Some(repo.into()),
+            files: vec![],
+            status,
+            decisions: vec![],
+            patch_sha256: String::new(),
+            accepted_sha256: String::new(),
+        }
+    }
+
+    #[test]
+    fn decisions_are_numbered_like_the_submitted_patch() {
+        let diff = "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,6 +1,6 @@
+ a
+-b
++B
+ c
+ d
+-e
++E
+ f
+@@ -20,2 +20,3 @@
+ t
++u
+ v
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        review.split_hunk(0, 0);
+        review.edit_hunk(0, 2, " t\n+u\n+w\n v\n").unwrap();
+        review.decide_rest(Status::Accepted);
+        let received = Received {
+            id: String::new(),
+            addr: None,
+            client: None,
+            at: Utc::now(),
+            patch: crate::models::Patch {
+                patch: diff.into(),
+                ..Default::default()
+            },
+        };
+        let entry = Entry::new(&received, &review.response());
+        let decisions: Vec<_> = entry
+            .decisions
+            .iter()
+            .map(|d| (d.hunk, d.changes.clone()))
+            .collect();
+        assert_eq!(
+            decisions,
+            vec![
+                (Some(0), vec![Change::Split]),
+                (Some(0), vec![Change::Split]),
+                (Some(1), vec![Change::Edited]),
+            ]
+        );
+        assert_ne!(entry.accepted_sha256, entry.patch_sha256);
+    }
+
+    #[test]
+    fn queries_filter_by_repo_date_and_status() {
+        let query = Query {
+            path: PathBuf::new(),
+            repo: Some(Pattern::new("payments-*").unwrap()),
+            client: None,
+            status: Some(Outcome::Accepted),
+            since: Some(NaiveDate::from_ymd_opt(2025, 3, 1).unwrap()),
+            until: Some(NaiveDate::from_ymd_opt(2025, 3, 31).unwrap()),
+            json: false,
+        };
+        let matches = |at, repo, status| query.matches(&entry(at, repo, status));
+        assert!(matches(
+            "2025-03-01T00:00:00Z",
+            "payments-api",
+            Outcome::Accepted
+        ));
+        assert!(matches(
+            "2025-03-31T23:59:59Z",
+            "payments-web",
+            Outcome::Accepted
+        ));
+        assert!(!matches(
+            "2025-04-01T00:00:00Z",
+            "payments-api",
+            Outcome::Accepted
+        ));
+        assert!(!matches("2025-03-15T12:00:00Z", "docs", Outcome::Accepted));
+        assert!(!matches(
+            "2025-03-15T12:00:00Z",
+            "payments-api",
+            Outcome::Partial
+        ));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{fs, net::SocketAddr, path::Path};
+
+use anyhow::Context;
+use log::warn;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Callback, ErrorResponse, Request, Response},
+    http::{header::AUTHORIZATION, StatusCode},
+};
+
+use crate::config::Credential;
+
+/// bearer tokens that clients must present (as `Authorization: Bearer <token>`
+/// when opening the websocket) before they can submit patches, along with the
+/// name of the client each one belongs to, if known
+#[derive(Debug)]
+pub struct Tokens(Vec<(Option<String>, String)>);
+
+impl Tokens {
+    /// reads one (anonymous) token per line, ignoring blank lines and `#` comments
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read tokens from {}", path.display()))?;
+        let tokens: Vec<_> = contents
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|token| (None, token.to_string()))
+            .collect();
+        anyhow::ensure!(!tokens.is_empty(), "no tokens in {}", path.display());
+        Ok(Tokens(tokens))
+    }
+
+    pub fn named(clients: &[Credential]) -> Self {
+        Tokens(
+            clients
+                .iter()
+                .map(|c| (Some(c.name.clone()), c.token.clone()))
+                .collect(),
+        )
+    }
+
+    /// checks a websocket upgrade request's bearer token, returning whose it is
+    fn authenticate(&self, request: &Request) -> Result<Option<&str>, &'static str> {
+        let presented = request
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        let Some(presented) = presented else {
+            return Err("missing bearer token");
+        };
+        self.0
+            .iter()
+            .find(|(_, known)| constant_time_eq(known, presented))
+            .map(|(name, _)| name.as_deref())
+            .ok_or("invalid token")
+    }
+}
+
+/// websocket handshake callback that turns away clients without a known token,
+/// recording the name of the ones it lets in
+pub struct Authenticate<'a> {
+    pub tokens: &'a Tokens,
+    pub addr: SocketAddr,
+    pub client: &'a mut Option<String>,
+}
+
+impl Callback for Authenticate<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        match self.tokens.authenticate(request) {
+            Ok(client) => {
+                *self.client = client.map(String::from);
+                Ok(response)
+            }
+            Err(reason) => {
+                warn!("Rejected client {}: {}", self.addr, reason);
+                let mut response = ErrorResponse::new(Some(reason.to_string()));
+                *response.status_mut() = StatusCode::UNAUTHORIZED;
+                Err(response)
+            }
+        }
+    }
+}
+
+/// compares without short-circuiting, so response times don't leak how much of a token matched
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
@@ -1,24 +1,51 @@
-use std::{path::PathBuf, process::Command};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
 
-use anyhow::{bail, Context};
+use anyhow::{anyhow, bail, Context};
 use futures_util::{SinkExt, StreamExt};
-use git2::Repository;
+use git2::{
+    build::CheckoutBuilder, ApplyLocation, Delta, Diff, DiffFile, DiffFindOptions, DiffOptions,
+    Repository, RevparseMode, Tree,
+};
 use log::{debug, info, warn};
 use octocrab::Octocrab;
 use prost::Message as _;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::{
+        client::IntoClientRequest,
+        http::{header::AUTHORIZATION, HeaderValue, StatusCode},
+        Error as WsError, Message,
+    },
+};
 
 use crate::{
     args::{ClientMode, GithubClientArgs, LocalClientArgs},
-    models::{patch_response::Status, Patch, PatchResponse},
+    models::{
+        client_message, file_change::Kind, patch_response::Status, server_message, ClientMessage,
+        ContextRequest, ContextResponse, FileChange, Patch, PatchResponse, ServerMessage,
+    },
+    tls,
 };
 
 const SERVER_URL: &str = "ws://127.0.0.1:8443";
+const DEFAULT_COMMIT_TEMPLATE: &str = "{metadata}\n\n{message}";
+/// how long to wait before reconnecting the first time, doubling each time after that
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Clone, Debug)]
 pub struct Client {
     mode: Mode,
     metadata: Option<String>,
+    server_url: String,
+    ca_cert: Option<PathBuf>,
+    token: Option<String>,
+    retries: u32,
 }
 
 impl From<ClientMode> for Client {
@@ -32,6 +59,10 @@ impl From<ClientMode> for Client {
         Client {
             mode: mode_enum,
             metadata: mode.metadata,
+            server_url: server_url(mode.server.as_deref().unwrap_or(SERVER_URL)),
+            ca_cert: mode.ca_cert,
+            token: mode.token,
+            retries: mode.retries,
         }
     }
 }
@@ -45,14 +76,408 @@ enum Mode {
 #[derive(Clone, Debug, Default)]
 struct Local {
     path: PathBuf,
+    source: Source,
+    /// set when accepted changes should be committed
+    commit_template: Option<String>,
+    discard_rejected: bool,
 }
 
 impl From<LocalClientArgs> for Local {
     fn from(args: LocalClientArgs) -> Self {
+        let commit_template = args.commit.then(|| {
+            args.commit_template
+                .unwrap_or(DEFAULT_COMMIT_TEMPLATE.to_string())
+        });
+        let source = match (args.staged, args.rev) {
+            (_, Some(rev)) => Source::Rev(rev),
+            (true, None) => Source::Staged,
+            (false, None) => Source::Workdir {
+                untracked: !args.no_untracked,
+            },
+        };
         Local {
             path: args.path.unwrap_or(".".into()),
+            source,
+            commit_template,
+            discard_rejected: args.discard_rejected,
+        }
+    }
+}
+
+/// what a local client diffs to build its patch
+#[derive(Clone, Debug)]
+enum Source {
+    /// unstaged changes (index to workdir), optionally including untracked files
+    Workdir { untracked: bool },
+    /// staged changes (HEAD to index)
+    Staged,
+    /// a commit (against its first parent) or a range of commits
+    Rev(String),
+}
+
+impl Default for Source {
+    fn default() -> Self {
+        Source::Workdir { untracked: true }
+    }
+}
+
+impl Local {
+    fn open(&self) -> anyhow::Result<Repository> {
+        let absolute_path = std::env::current_dir()?.join(&self.path);
+        let repo = Repository::open(absolute_path)?;
+        info!("Successfully opened git repository");
+        Ok(repo)
+    }
+
+    fn diff<'r>(&self, repo: &'r Repository) -> anyhow::Result<Diff<'r>> {
+        self.diff_with(repo, DiffOptions::new())
+    }
+
+    /// the diff of just `paths`, with every file treated as binary
+    fn diff_binary<'r>(
+        &self,
+        repo: &'r Repository,
+        paths: &BTreeSet<PathBuf>,
+    ) -> anyhow::Result<Diff<'r>> {
+        let mut opts = DiffOptions::new();
+        opts.force_binary(true).disable_pathspec_match(true);
+        for path in paths {
+            opts.pathspec(path);
+        }
+        self.diff_with(repo, opts)
+    }
+
+    fn diff_with<'r>(
+        &self,
+        repo: &'r Repository,
+        mut opts: DiffOptions,
+    ) -> anyhow::Result<Diff<'r>> {
+        // include binary contents so accepted binary changes can be staged
+        opts.show_binary(true);
+        let mut diff = match &self.source {
+            Source::Workdir { untracked } => {
+                // new files show up as additions of their full contents
+                opts.include_untracked(*untracked)
+                    .recurse_untracked_dirs(*untracked)
+                    .show_untracked_content(*untracked);
+                repo.diff_index_to_workdir(Some(&repo.index()?), Some(&mut opts))?
+            }
+            Source::Staged => {
+                let index = repo.index()?;
+                repo.diff_tree_to_index(head_tree(repo)?.as_ref(), Some(&index), Some(&mut opts))?
+            }
+            Source::Rev(spec) => {
+                let (old, new) = rev_trees(repo, spec)?;
+                repo.diff_tree_to_tree(old.as_ref(), Some(&new), Some(&mut opts))?
+            }
+        };
+        let mut find = DiffFindOptions::new();
+        find.renames(true)
+            .copies(true)
+            .for_untracked(matches!(self.source, Source::Workdir { untracked: true }));
+        diff.find_similar(Some(&mut find))?;
+        Ok(diff)
+    }
+
+    /// the file at `path` as it is on the diff's new side
+    fn read_new(&self, path: &str) -> anyhow::Result<String> {
+        let repo = self.open()?;
+        let bytes = match &self.source {
+            Source::Workdir { .. } => {
+                let workdir = repo.workdir().context("repo has no working tree")?;
+                std::fs::read(workdir.join(path))?
+            }
+            Source::Staged => {
+                let entry = repo
+                    .index()?
+                    .get_path(Path::new(path), 0)
+                    .with_context(|| format!("{path} isn't in the index"))?;
+                repo.find_blob(entry.id)?.content().to_vec()
+            }
+            Source::Rev(spec) => {
+                let (_, tree) = rev_trees(&repo, spec)?;
+                let entry = tree.get_path(Path::new(path))?;
+                repo.find_blob(entry.id())?.content().to_vec()
+            }
+        };
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// stages the accepted patch into the repo's index, like `git add -p` would
+    fn stage(&self, submitted: &str, patch: &str) -> anyhow::Result<()> {
+        let repo = self.open()?;
+        match self.source {
+            Source::Workdir { .. } => {}
+            // start over from HEAD so only the accepted hunks remain staged
+            Source::Staged => {
+                let head = repo
+                    .head()
+                    .ok()
+                    .map(|h| h.peel(git2::ObjectType::Commit))
+                    .transpose()?;
+                let paths = diff_paths(&Diff::from_buffer(submitted.as_bytes())?);
+                repo.reset_default(head.as_ref(), paths)?;
+            }
+            // history is reviewed in place, there's nothing to stage
+            Source::Rev(_) => return Ok(()),
+        }
+        if patch.is_empty() {
+            return Ok(());
+        }
+        let diff = Diff::from_buffer(patch.as_bytes()).context("server sent an invalid patch")?;
+        repo.apply(&diff, ApplyLocation::Index, None)
+            .context("accepted patch no longer applies to the index")?;
+        info!(
+            "Staged {} file(s) in {}",
+            diff.stats()?.files_changed(),
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    /// resets every file in the submitted patch to the index, which (after staging)
+    /// holds exactly the accepted hunks, throwing away the rejected ones
+    fn discard_rejected(&self, submitted: &str) -> anyhow::Result<()> {
+        if !self.discard_rejected {
+            return Ok(());
+        }
+        let repo = self.open()?;
+        let diff = Diff::from_buffer(submitted.as_bytes())?;
+        let mut checkout = CheckoutBuilder::new();
+        // rejected untracked files were never staged, so they're removed outright
+        checkout.force().remove_untracked(true);
+        // the paths are file names, not globs that could match files nobody reviewed
+        checkout.disable_pathspec_match(true);
+        for path in diff_paths(&diff) {
+            checkout.path(path);
+        }
+        repo.checkout_index(None, Some(&mut checkout))
+            .context("failed to discard rejected hunks")?;
+        info!("Discarded rejected hunks in {}", self.path.display());
+        Ok(())
+    }
+
+    /// `--commit` commits the whole index, so in working tree mode it mustn't already
+    /// hold changes the reviewer won't see
+    fn check_commit(&self, repo: &Repository) -> anyhow::Result<()> {
+        if self.commit_template.is_none() || !matches!(self.source, Source::Workdir { .. }) {
+            return Ok(());
+        }
+        let staged =
+            repo.diff_tree_to_index(head_tree(repo)?.as_ref(), Some(&repo.index()?), None)?;
+        if staged.deltas().len() > 0 {
+            bail!(
+                "{} file(s) are already staged and would be committed without review: \
+                 commit or unstage them first, or review them with --staged",
+                staged.deltas().len()
+            );
+        }
+        Ok(())
+    }
+
+    /// commits whatever is staged, with a message rendered from the commit template
+    fn commit(
+        &self,
+        patch: &str,
+        metadata: Option<&str>,
+        message: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let Some(template) = &self.commit_template else {
+            return Ok(());
+        };
+        if patch.is_empty() {
+            return Ok(());
+        }
+        let repo = self.open()?;
+        let diff = Diff::from_buffer(patch.as_bytes())?;
+        let files: Vec<_> = diff_paths(&diff)
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        let mut commit_message = render_commit_template(
+            template,
+            metadata.unwrap_or_default(),
+            &repo_name(&repo),
+            &files.join(", "),
+            message.unwrap_or_default(),
+        );
+        if commit_message.is_empty() {
+            commit_message = format!("Apply reviewed changes to {}", files.join(", "));
+        }
+
+        let signature = repo.signature()?;
+        let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+        // an unborn branch has nothing to parent the commit on
+        let parent = match repo.head() {
+            Ok(head) => Some(head.peel_to_commit()?),
+            Err(_) => None,
+        };
+        let parents: Vec<_> = parent.iter().collect();
+        let oid = repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            &commit_message,
+            &tree,
+            &parents,
+        )?;
+        info!("Committed accepted changes as {}", oid);
+        Ok(())
+    }
+}
+
+/// the trees a revspec's diff goes between
+fn rev_trees<'r>(repo: &'r Repository, spec: &str) -> anyhow::Result<(Option<Tree<'r>>, Tree<'r>)> {
+    let revspec = repo
+        .revparse(spec)
+        .with_context(|| format!("couldn't resolve `{spec}`"))?;
+    match (revspec.from(), revspec.to()) {
+        (Some(from), Some(to)) => {
+            let from = if revspec.mode().contains(RevparseMode::MERGE_BASE) {
+                let base = repo.merge_base(from.id(), to.id())?;
+                repo.find_object(base, None)?
+            } else {
+                from.clone()
+            };
+            Ok((Some(from.peel_to_tree()?), to.peel_to_tree()?))
+        }
+        // a single commit is diffed against its first parent
+        (Some(rev), None) | (None, Some(rev)) => {
+            let commit = rev.peel_to_commit()?;
+            let parent = match commit.parents().next() {
+                Some(parent) => Some(parent.tree()?),
+                None => None,
+            };
+            Ok((parent, commit.tree()?))
+        }
+        (None, None) => bail!("`{spec}` doesn't name any commits"),
+    }
+}
+
+fn head_tree(repo: &Repository) -> anyhow::Result<Option<Tree<'_>>> {
+    match repo.head() {
+        Ok(head) => Ok(Some(head.peel_to_tree()?)),
+        // nothing has been committed yet
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// the name of the checkout's directory
+fn repo_name(repo: &Repository) -> String {
+    repo.workdir()
+        .and_then(|p| p.file_name())
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// renders the diff as a unified patch, leaving out files with a path in `skip`,
+/// along with the paths of files whose text isn't valid utf-8
+fn print_patch(
+    diff: &Diff,
+    skip: &BTreeSet<PathBuf>,
+) -> anyhow::Result<(String, BTreeSet<PathBuf>)> {
+    let mut patch = String::new();
+    let mut invalid = BTreeSet::new();
+    diff.print(git2::DiffFormat::Patch, |d, _h, l| {
+        let paths = [d.old_file().path(), d.new_file().path()];
+        if paths.iter().flatten().any(|p| skip.contains(*p)) {
+            return true;
+        }
+        match l.origin() {
+            '+' | '-' | ' ' => patch.push(l.origin()),
+            _ => {}
+        };
+        // git2 only hands us text for files it didn't detect as binary
+        match std::str::from_utf8(l.content()) {
+            Ok(content) => patch.push_str(content),
+            Err(_) => invalid.extend(paths.into_iter().flatten().map(PathBuf::from)),
+        }
+        true
+    })?;
+    Ok((patch, invalid))
+}
+
+/// every path touched by a diff, including both sides of renames
+fn diff_paths(diff: &Diff) -> Vec<PathBuf> {
+    let mut paths: Vec<_> = diff
+        .deltas()
+        .flat_map(|d| [d.old_file().path(), d.new_file().path()])
+        .flatten()
+        .map(PathBuf::from)
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// describes each file in the patch, so the server can show file-level changes
+fn file_changes(patch: &str) -> Vec<FileChange> {
+    let diff = match Diff::from_buffer(patch.as_bytes()) {
+        Ok(diff) => diff,
+        Err(e) => {
+            warn!("Couldn't describe file changes: {}", e);
+            return vec![];
+        }
+    };
+    let path = |file: DiffFile| {
+        file.path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default()
+    };
+    diff.deltas()
+        .map(|delta| {
+            let kind = match delta.status() {
+                Delta::Added | Delta::Untracked => Kind::Added,
+                Delta::Deleted => Kind::Deleted,
+                Delta::Renamed => Kind::Renamed,
+                Delta::Copied => Kind::Copied,
+                Delta::Typechange => Kind::TypeChanged,
+                _ => Kind::Modified,
+            };
+            FileChange {
+                kind: kind.into(),
+                old_path: path(delta.old_file()),
+                new_path: path(delta.new_file()),
+                old_mode: u32::from(delta.old_file().mode()),
+                new_mode: u32::from(delta.new_file().mode()),
+                binary: delta.flags().is_binary(),
+            }
+        })
+        .collect()
+}
+
+/// accepts bare `host:port` addresses, assuming an unencrypted websocket
+fn server_url(server: &str) -> String {
+    if server.contains("://") {
+        server.to_string()
+    } else {
+        format!("ws://{server}")
+    }
+}
+
+/// fills in the commit template's placeholders, dropping blank lines left by empty ones
+fn render_commit_template(
+    template: &str,
+    metadata: &str,
+    repo: &str,
+    files: &str,
+    message: &str,
+) -> String {
+    let rendered = template
+        .replace("{metadata}", metadata)
+        .replace("{repo}", repo)
+        .replace("{files}", files)
+        .replace("{message}", message);
+
+    let mut lines: Vec<&str> = vec![];
+    for line in rendered.lines().map(str::trim_end) {
+        let prev_blank = lines.last().is_none_or(|l| l.is_empty());
+        if !(line.is_empty() && prev_blank) {
+            lines.push(line);
         }
     }
+    lines.join("\n").trim().to_string()
 }
 
 #[derive(Clone, Debug)]
@@ -76,16 +501,93 @@ impl From<GithubClientArgs> for Github {
     }
 }
 
+/// the commit a pull request's diff was fetched at, which context is read from
+struct PullHead {
+    octocrab: Octocrab,
+    owner: String,
+    repo: String,
+    sha: String,
+}
+
+impl PullHead {
+    async fn read(&self, path: &str) -> anyhow::Result<String> {
+        let response = self
+            .octocrab
+            .repos(&self.owner, &self.repo)
+            .raw_file(self.sha.clone(), path)
+            .await?;
+        let response = octocrab::map_github_error(response).await?;
+        Ok(self.octocrab.body_to_string(response).await?)
+    }
+}
+
 impl Client {
-    pub async fn run(&self) -> anyhow::Result<()> {
-        let unified_patch = match &self.mode {
-            Mode::Local(Local { path }) => {
-                let absolute_path = std::env::current_dir()?.join(path);
-                let repo = Repository::open(absolute_path)?;
-                info!("Successfully opened git repository");
+    /// answers the server's request for more of one of the `patch`'s files
+    async fn context(
+        &self,
+        patch: &Patch,
+        head: Option<&PullHead>,
+        request: &ContextRequest,
+    ) -> ContextResponse {
+        let mut response = ContextResponse {
+            file: request.file,
+            hunk: request.hunk,
+            start: request.start,
+            ..Default::default()
+        };
+        // nothing outside the patch is the server's business
+        let in_patch = patch
+            .files
+            .iter()
+            .any(|f| f.kind() != Kind::Deleted && f.new_path == request.path);
+        let contents = match (&self.mode, head) {
+            _ if !in_patch => Err(anyhow!("{} isn't part of the patch", request.path)),
+            (Mode::Local(local), _) => local.read_new(&request.path),
+            (Mode::Github(_), Some(head)) => head.read(&request.path).await,
+            (Mode::Github(_), None) => Err(anyhow!("the pull request's head is unknown")),
+        };
+        match contents {
+            Ok(contents) => {
+                let start = request.start.max(1) as usize;
+                let end = request.end as usize;
+                response.lines = contents
+                    .lines()
+                    .skip(start - 1)
+                    .take((end + 1).saturating_sub(start))
+                    .map(String::from)
+                    .collect();
+            }
+            Err(e) => {
+                warn!("Couldn't read {} for more context: {:#}", request.path, e);
+                response.error = Some(format!("{e:#}"));
+            }
+        }
+        response
+    }
 
-                let index = repo.index()?;
-                let diff = repo.diff_index_to_workdir(Some(&index), None)?;
+    /// acts on the reviewer's decisions for the `submitted` patch
+    fn apply_response(&self, submitted: &str, response: &PatchResponse) -> anyhow::Result<()> {
+        match &self.mode {
+            Mode::Local(local) => {
+                local.stage(submitted, &response.patch)?;
+                local.discard_rejected(submitted)?;
+                local.commit(
+                    &response.patch,
+                    self.metadata.as_deref(),
+                    response.commit_message.as_deref(),
+                )
+            }
+            // nothing to stage locally for a remote PR
+            Mode::Github(_) => Ok(()),
+        }
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let (unified_patch, repo_name, head) = match &self.mode {
+            Mode::Local(local) => {
+                let repo = local.open()?;
+                local.check_commit(&repo)?;
+                let diff = local.diff(&repo)?;
 
                 if diff.stats()?.files_changed() == 0 {
                     debug!("Added:   {}", diff.stats()?.insertions());
@@ -94,15 +596,18 @@ impl Client {
                     bail!("no files changed...")
                 }
 
-                let mut diff_str = String::new();
-                diff.print(git2::DiffFormat::Patch, |_d, _h, l| {
-                    match l.origin() {
-                        '+' | '-' | ' ' => diff_str.push(l.origin()),
-                        _ => {}
-                    };
-                    diff_str.push_str(std::str::from_utf8(l.content()).expect("all utf-8"));
-                    true
-                })?;
+                let (mut diff_str, invalid) = print_patch(&diff, &BTreeSet::new())?;
+                // the reviewer can't be shown text that isn't utf-8 as it really is, so
+                // those files are sent as binary patches instead
+                if !invalid.is_empty() {
+                    for path in &invalid {
+                        info!("{} isn't valid utf-8, sending it as binary", path.display());
+                    }
+                    let (text, _) = print_patch(&diff, &invalid)?;
+                    let (binary, _) =
+                        print_patch(&local.diff_binary(&repo, &invalid)?, &BTreeSet::new())?;
+                    diff_str = text + &binary;
+                }
 
                 debug!("\nDiff preview (first 10 lines):");
                 debug!(
@@ -112,7 +617,7 @@ impl Client {
                 if diff_str.lines().count() > 10 {
                     debug!("... ({} more lines)", diff_str.lines().count() - 10);
                 }
-                diff_str
+                (diff_str, repo_name(&repo), None)
             }
             Mode::Github(Github { owner, repo, pr }) => {
                 let gh_auth = Command::new("gh")
@@ -128,49 +633,367 @@ impl Client {
                 debug!("authed w/ github");
                 let diff = octocrab.pulls(owner, repo).get_patch(*pr).await?;
                 debug!("fetched pull request diff");
-                diff
+                let sha = octocrab.pulls(owner, repo).get(*pr).await?.head.sha;
+                let head = PullHead {
+                    octocrab,
+                    owner: owner.clone(),
+                    repo: repo.clone(),
+                    sha,
+                };
+                (diff, format!("{owner}/{repo}"), Some(head))
             }
         };
 
-        let (ws_stream, _) = connect_async(SERVER_URL)
-            .await
-            .context("(is the server listening?)")
-            .expect("Failed to connect");
-        info!("WebSocket handshake has been successfully completed");
-        let (mut ws_tx, mut ws_rx) = ws_stream.split();
         let patch = Patch {
+            files: file_changes(&unified_patch),
             metadata: self.metadata.clone(),
             patch: unified_patch,
+            commit: matches!(&self.mode, Mode::Local(l) if l.commit_template.is_some()),
+            repo: Some(repo_name),
+            // the same for every reconnection in this run, and no other
+            id: format!("{:032x}", rand::random::<u128>()),
         };
-        ws_tx
-            .send(patch.encode_to_vec().into())
-            .await
-            .expect("failed to send");
-        info!("Sent patch to server");
-
-        match ws_rx.next().await {
-            Some(Ok(Message::Binary(b))) => {
-                info!("got response from server");
-                let response = PatchResponse::decode(b).unwrap();
-                match response.status.try_into()? {
-                    Status::Accepted => return Ok(()),
-                    Status::Rejected => {
-                        info!("patch was rejected!");
-                        std::process::exit(1)
-                    }
-                    Status::Unknown => bail!("who knows..."),
+
+        // once the server has had the patch, it's worth waiting out a restart for its decision
+        let mut connected = false;
+        let mut failures = 0;
+        let mut backoff = INITIAL_BACKOFF;
+        let response = loop {
+            let error = match self.submit(&patch, head.as_ref()).await? {
+                Outcome::Decided(response) => break response,
+                Outcome::Unreachable(e) if !connected => return Err(e),
+                Outcome::Unreachable(e) => e,
+                Outcome::Dropped(e) => {
+                    connected = true;
+                    failures = 0;
+                    backoff = INITIAL_BACKOFF;
+                    e
                 }
+            };
+            failures += 1;
+            if failures > self.retries {
+                return Err(error.context("gave up reconnecting to the server"));
             }
-            Some(Ok(Message::Close(_))) => {
-                info!("Server disconnected.");
+            warn!(
+                "Lost the server ({:#}), reconnecting in {}s",
+                error,
+                backoff.as_secs()
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        };
+
+        info!("got response from server");
+        match response.status.try_into()? {
+            Status::Accepted => {
+                info!("patch was accepted!");
+                self.apply_response(&patch.patch, &response)?;
             }
-            Some(Err(e)) => {
-                warn!("Err on socket: {}", e);
+            Status::Partial => {
+                let accepted = response
+                    .hunks
+                    .iter()
+                    .filter(|h| h.status() == Status::Accepted)
+                    .count();
+                info!(
+                    "patch was partially accepted ({}/{} hunks)",
+                    accepted,
+                    response.hunks.len()
+                );
+                debug!("accepted patch:\n{}", response.patch);
+                self.apply_response(&patch.patch, &response)?;
             }
-            _ => {
-                warn!("Not sure how to handle... (got unexpected message)");
+            Status::Rejected => {
+                info!("patch was rejected!");
+                self.apply_response(&patch.patch, &response)?;
+                std::process::exit(1)
             }
-        };
+            Status::Unknown => bail!("who knows..."),
+        }
         Ok(())
     }
+
+    /// sends the patch, then answers the server until the reviewer decides
+    async fn submit(&self, patch: &Patch, head: Option<&PullHead>) -> anyhow::Result<Outcome> {
+        let mut request = self
+            .server_url
+            .as_str()
+            .into_client_request()
+            .with_context(|| format!("invalid server url {}", self.server_url))?;
+        if let Some(token) = &self.token {
+            request.headers_mut().insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}")).context("invalid token")?,
+            );
+        }
+        let connector = self.ca_cert.as_deref().map(tls::connector).transpose()?;
+        let (ws_stream, _) =
+            match connect_async_tls_with_config(request, None, false, connector).await {
+                Ok(connected) => connected,
+                Err(WsError::Http(response)) if response.status() == StatusCode::UNAUTHORIZED => {
+                    let reason = response.body().as_deref().map(String::from_utf8_lossy);
+                    bail!(
+                        "{} rejected our token ({}), check --token",
+                        self.server_url,
+                        reason.unwrap_or_default()
+                    )
+                }
+                Err(e) => {
+                    return Ok(Outcome::Unreachable(anyhow!(e).context(format!(
+                        "couldn't connect to {} (is the server listening?)",
+                        self.server_url
+                    ))))
+                }
+            };
+        info!("WebSocket handshake has been successfully completed");
+        let (mut ws_tx, mut ws_rx) = ws_stream.split();
+        let message = ClientMessage {
+            message: Some(client_message::Message::Patch(patch.clone())),
+        };
+        if let Err(e) = ws_tx.send(message.encode_to_vec().into()).await {
+            return Ok(Outcome::Dropped(e.into()));
+        }
+        info!("Sent patch {} to server", patch.id);
+
+        // the server may ask for more of the patch's files while it's being reviewed
+        loop {
+            match ws_rx.next().await {
+                Some(Ok(Message::Binary(b))) => match ServerMessage::decode(b)?.message {
+                    Some(server_message::Message::Context(request)) => {
+                        debug!(
+                            "server asked for lines {}-{} of {}",
+                            request.start, request.end, request.path
+                        );
+                        let message = ClientMessage {
+                            message: Some(client_message::Message::Context(
+                                self.context(patch, head, &request).await,
+                            )),
+                        };
+                        if let Err(e) = ws_tx.send(message.encode_to_vec().into()).await {
+                            return Ok(Outcome::Dropped(e.into()));
+                        }
+                    }
+                    Some(server_message::Message::Response(response)) => {
+                        return Ok(Outcome::Decided(response))
+                    }
+                    None => warn!("Not sure how to handle... (got an empty message)"),
+                },
+                Some(Ok(Message::Close(Some(frame)))) if !frame.reason.is_empty() => {
+                    bail!("server closed the connection: {}", frame.reason)
+                }
+                Some(Ok(Message::Close(_))) | None => {
+                    info!("Server disconnected.");
+                    return Ok(Outcome::Dropped(anyhow!(
+                        "the server closed the connection"
+                    )));
+                }
+                Some(Err(e)) => {
+                    warn!("Err on socket: {}", e);
+                    return Ok(Outcome::Dropped(e.into()));
+                }
+                Some(Ok(_)) => warn!("Not sure how to handle... (got unexpected message)"),
+            }
+        }
+    }
+}
+
+/// how a connection to the server ended
+enum Outcome {
+    Decided(PatchResponse),
+    /// the server couldn't be reached
+    Unreachable(anyhow::Error),
+    /// the server went away after it was sent the patch
+    Dropped(anyhow::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_commit_template() {
+        let render = |template, message| {
+            render_commit_template(template, "bump deps", "patchpal", "a.rs, b.rs", message)
+        };
+        assert_eq!(render(DEFAULT_COMMIT_TEMPLATE, ""), "bump deps");
+        assert_eq!(
+            render(DEFAULT_COMMIT_TEMPLATE, "looks good"),
+            "bump deps\n\nlooks good"
+        );
+        assert_eq!(
+            render("[{repo}] {metadata}\n\n{message}\n\nFiles: {files}", ""),
+            "[patchpal] bump deps\n\nFiles: a.rs, b.rs"
+        );
+    }
+
+    /// a repo with `files` committed, and a client for it
+    fn committed(dir: &Path, files: &[(&str, &str)], local: Local) -> (Repository, Local) {
+        let repo = Repository::init(dir).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "patchpal").unwrap();
+        config
+            .set_str("user.email", "patchpal@example.com")
+            .unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            let path = Path::new(path);
+            std::fs::create_dir_all(dir.join(path).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(path), contents).unwrap();
+            index.add_path(path).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = repo.signature().unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        drop(tree);
+        let local = Local {
+            path: dir.to_path_buf(),
+            ..local
+        };
+        (repo, local)
+    }
+
+    #[test]
+    fn accepted_hunks_are_staged_and_committed_and_the_rest_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let lines = |changed: &[(usize, &str)]| -> String {
+            (1..=20)
+                .map(|n| match changed.iter().find(|(line, _)| *line == n) {
+                    Some((_, text)) => format!("{text}\n"),
+                    None => format!("{n}\n"),
+                })
+                .collect()
+        };
+        let (repo, local) = committed(
+            dir,
+            &[("a.txt", &lines(&[]))],
+            Local {
+                commit_template: Some(DEFAULT_COMMIT_TEMPLATE.to_string()),
+                discard_rejected: true,
+                ..Default::default()
+            },
+        );
+        let changed = lines(&[(2, "two"), (18, "eighteen")]);
+        std::fs::write(dir.join("a.txt"), changed).unwrap();
+        std::fs::write(dir.join("new.txt"), "new\n").unwrap();
+
+        let (submitted, _) = print_patch(&local.diff(&repo).unwrap(), &BTreeSet::new()).unwrap();
+        let mut review = crate::review::Review::new(&submitted, &[]).unwrap();
+        review.decide(Status::Accepted);
+        review.decide_rest(Status::Rejected);
+        let patch = review.response().patch;
+        local.stage(&submitted, &patch).unwrap();
+        local.discard_rejected(&submitted).unwrap();
+        local.commit(&patch, Some("bump"), None).unwrap();
+
+        let accepted = lines(&[(2, "two")]);
+        let head = repo.head().unwrap().peel_to_commit().unwrap();
+        assert_eq!(head.message(), Some("bump"));
+        let blob = head.tree().unwrap().get_path(Path::new("a.txt")).unwrap();
+        let committed = repo.find_blob(blob.id()).unwrap();
+        assert_eq!(committed.content(), accepted.as_bytes());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("a.txt")).unwrap(),
+            accepted
+        );
+        assert!(!dir.join("new.txt").exists());
+    }
+
+    #[test]
+    fn discarding_leaves_unreviewed_files_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        let (repo, local) = committed(
+            dir,
+            &[("app/[id].tsx", "old\n")],
+            Local {
+                source: Source::Workdir { untracked: false },
+                discard_rejected: true,
+                ..Default::default()
+            },
+        );
+        std::fs::write(dir.join("app/[id].tsx"), "new\n").unwrap();
+        // matches `app/[id].tsx` as a glob
+        std::fs::write(dir.join("app/i.tsx"), "untracked\n").unwrap();
+
+        let (submitted, _) = print_patch(&local.diff(&repo).unwrap(), &BTreeSet::new()).unwrap();
+        local.stage(&submitted, "").unwrap();
+        local.discard_rejected(&submitted).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("app/[id].tsx")).unwrap(),
+            "old\n"
+        );
+        assert!(dir.join("app/i.tsx").exists());
+    }
+
+    #[test]
+    fn commit_refuses_changes_staged_beforehand() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let local = Local {
+            path: dir.path().to_path_buf(),
+            commit_template: Some(DEFAULT_COMMIT_TEMPLATE.to_string()),
+            ..Default::default()
+        };
+        assert!(local.check_commit(&repo).is_ok());
+
+        std::fs::write(dir.path().join("a.txt"), "staged\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a.txt")).unwrap();
+        index.write().unwrap();
+        assert!(local.check_commit(&repo).is_err());
+        // with --staged, what's staged is exactly what gets reviewed
+        let staged = Local {
+            source: Source::Staged,
+            ..local
+        };
+        assert!(staged.check_commit(&repo).is_ok());
+    }
+
+    #[tokio::test]
+    async fn context_is_only_given_for_files_in_the_patch() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path();
+        Repository::init(dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        std::fs::write(dir.join("secret"), "hunter2\n").unwrap();
+        let client = Client {
+            mode: Mode::Local(Local {
+                path: dir.to_path_buf(),
+                ..Default::default()
+            }),
+            metadata: None,
+            server_url: String::new(),
+            ca_cert: None,
+            token: None,
+            retries: 0,
+        };
+        let patch = Patch {
+            files: vec![FileChange {
+                old_path: "a.txt".into(),
+                new_path: "a.txt".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let (client, patch) = (&client, &patch);
+        let context = |path: &str| {
+            let request = ContextRequest {
+                path: path.into(),
+                start: 2,
+                end: 3,
+                ..Default::default()
+            };
+            async move { client.context(patch, None, &request).await }
+        };
+
+        assert_eq!(context("a.txt").await.lines, vec!["two", "three"]);
+        for path in ["secret", "../secret", dir.join("secret").to_str().unwrap()] {
+            let response = context(path).await;
+            assert!(response.lines.is_empty());
+            assert!(response.error.is_some());
+        }
+    }
 }
@@ -0,0 +1,182 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context};
+use glob::Pattern;
+use toml_edit::{DocumentMut, Item, Table};
+
+/// the server's `--config` file, e.g.
+///
+/// ```toml
+/// # patches matching no rule are allowed, unless this is "deny"
+/// default = "allow"
+///
+/// [[client]]
+/// name = "codemod-bot"
+/// token = "..."
+///
+/// # the first rule matching a patch's client and repo (`*` globs) decides
+/// [[rule]]
+/// action = "deny"
+/// client = "codemod-bot"
+/// repo = "payments-*"
+/// ```
+#[derive(Debug, Default)]
+pub struct ServerConfig {
+    pub clients: Vec<Credential>,
+    pub rules: Rules,
+}
+
+/// a named client and the token it authenticates with
+#[derive(Debug, Clone)]
+pub struct Credential {
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    deny_by_default: bool,
+}
+
+#[derive(Debug)]
+struct Rule {
+    allow: bool,
+    client: Option<Pattern>,
+    repo: Option<Pattern>,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("couldn't read config from {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("invalid config in {}", path.display()))
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let doc: DocumentMut = contents.parse()?;
+
+        let mut clients = vec![];
+        for table in tables(&doc, "client")? {
+            let name = string(table, "name")?.context("client without a name")?;
+            let token =
+                string(table, "token")?.with_context(|| format!("client `{name}` has no token"))?;
+            if clients.iter().any(|c: &Credential| c.name == name) {
+                bail!("client `{name}` is defined twice");
+            }
+            clients.push(Credential { name, token });
+        }
+
+        let mut rules = vec![];
+        for table in tables(&doc, "rule")? {
+            let allow = match string(table, "action")?.as_deref() {
+                Some("allow") => true,
+                Some("deny") => false,
+                _ => bail!("rules need an action of \"allow\" or \"deny\""),
+            };
+            let pattern = |key| -> anyhow::Result<Option<Pattern>> {
+                string(table, key)?
+                    .map(|p| Pattern::new(&p).with_context(|| format!("invalid {key} `{p}`")))
+                    .transpose()
+            };
+            rules.push(Rule {
+                allow,
+                client: pattern("client")?,
+                repo: pattern("repo")?,
+            });
+        }
+
+        let deny_by_default = match doc.get("default").map(|d| d.as_str()) {
+            None | Some(Some("allow")) => false,
+            Some(Some("deny")) => true,
+            _ => bail!("default must be \"allow\" or \"deny\""),
+        };
+
+        Ok(ServerConfig {
+            clients,
+            rules: Rules {
+                rules,
+                deny_by_default,
+            },
+        })
+    }
+}
+
+impl Rules {
+    /// whether a patch for `repo` from `client` may be reviewed. a rule naming a
+    /// client or repo never matches a patch that lacks one
+    pub fn allows(&self, client: Option<&str>, repo: Option<&str>) -> bool {
+        let matches = |pattern: &Option<Pattern>, value: Option<&str>| match pattern {
+            Some(pattern) => value.is_some_and(|v| pattern.matches(v)),
+            None => true,
+        };
+        self.rules
+            .iter()
+            .find(|rule| matches(&rule.client, client) && matches(&rule.repo, repo))
+            .map_or(!self.deny_by_default, |rule| rule.allow)
+    }
+}
+
+fn tables<'d>(doc: &'d DocumentMut, key: &str) -> anyhow::Result<Vec<&'d Table>> {
+    match doc.get(key) {
+        None => Ok(vec![]),
+        Some(Item::ArrayOfTables(tables)) => Ok(tables.iter().collect()),
+        Some(_) => bail!("`{key}` should be written as [[{key}]] tables"),
+    }
+}
+
+fn string(table: &Table, key: &str) -> anyhow::Result<Option<String>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(item) => match item.as_str() {
+            Some(s) => Ok(Some(s.to_string())),
+            None => bail!("`{key}` should be a string"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let config = ServerConfig::parse(
+            r#"
+            default = "deny"
+
+            [[client]]
+            name = "codemod-bot"
+            token = "t0k3n"
+
+            [[rule]]
+            action = "deny"
+            repo = "payments-*"
+
+            [[rule]]
+            action = "allow"
+            client = "codemod-bot"
+
+            [[rule]]
+            action = "allow"
+            repo = "docs"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.clients[0].name, "codemod-bot");
+        let rules = &config.rules;
+        assert!(rules.allows(Some("codemod-bot"), Some("backend")));
+        assert!(!rules.allows(Some("codemod-bot"), Some("payments-api")));
+        assert!(rules.allows(None, Some("docs")));
+        assert!(!rules.allows(None, Some("backend")));
+        assert!(!rules.allows(Some("someone"), None));
+    }
+
+    #[test]
+    fn rejects_malformed_configs() {
+        assert!(ServerConfig::parse("[[client]]\nname = \"a\"").is_err());
+        assert!(ServerConfig::parse("[[rule]]\naction = \"maybe\"").is_err());
+        assert!(ServerConfig::parse("default = \"sometimes\"").is_err());
+        assert!(ServerConfig::parse("[client]\nname = \"a\"\ntoken = \"b\"").is_err());
+    }
+}
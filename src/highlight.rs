@@ -0,0 +1,252 @@
+use std::{ops::Range, path::Path, sync::OnceLock};
+
+use log::debug;
+use ratatui::{
+    style::{Color, Style},
+    text::{Line, Span},
+};
+use syntect::{
+    easy::HighlightLines,
+    highlighting::{self, Theme, ThemeSet},
+    parsing::{SyntaxReference, SyntaxSet},
+};
+use unidiff::Hunk;
+
+use crate::review::FileDiff;
+
+const THEME: &str = "base16-ocean.dark";
+
+/// byte ranges within a line
+pub type Ranges = Vec<Range<usize>>;
+
+/// how to color each of a hunk's lines (indexed like [`Hunk::lines`])
+#[derive(Clone, Debug, Default)]
+pub struct HunkHighlights {
+    /// the lines split into syntax-colored spans, empty if the language isn't known
+    pub syntax: Vec<Line<'static>>,
+    /// byte ranges of each line that differ from the line it's paired with
+    pub changed: Vec<Ranges>,
+}
+
+fn syntaxes() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme() -> &'static Theme {
+    static THEME_SET: OnceLock<ThemeSet> = OnceLock::new();
+    &THEME_SET.get_or_init(ThemeSet::load_defaults).themes[THEME]
+}
+
+/// the language of the file at `path`, from its extension (or its name, e.g. `Makefile`)
+fn syntax_for(path: &str) -> Option<&'static SyntaxReference> {
+    let path = Path::new(path);
+    let syntaxes = syntaxes();
+    path.extension()
+        .and_then(|ext| syntaxes.find_syntax_by_extension(ext.to_str()?))
+        .or_else(|| syntaxes.find_syntax_by_extension(path.file_name()?.to_str()?))
+}
+
+/// highlights each of the file's hunks
+pub fn highlight_file(file: &FileDiff) -> Vec<HunkHighlights> {
+    let syntax = syntax_for(&file.patched.target_file);
+    if let Some(syntax) = syntax {
+        debug!("Highlighting {} as {}", file.path(), syntax.name);
+    }
+    file.patched
+        .hunks()
+        .iter()
+        .map(|hunk| HunkHighlights {
+            syntax: syntax.map_or_else(Vec::new, |syntax| highlight_hunk(hunk, syntax)),
+            changed: changed_ranges(hunk),
+        })
+        .collect()
+}
+
+/// the old and new sides are highlighted separately, so that e.g. a removed line
+/// opening a comment doesn't leak into the added lines after it
+fn highlight_hunk(hunk: &Hunk, syntax: &SyntaxReference) -> Vec<Line<'static>> {
+    let mut old = HighlightLines::new(syntax, theme());
+    let mut new = HighlightLines::new(syntax, theme());
+    hunk.lines()
+        .iter()
+        .map(|line| {
+            let value = format!("{}\n", line.value);
+            let highlighted = if line.is_added() {
+                new.highlight_line(&value, syntaxes())
+            } else if line.is_removed() {
+                old.highlight_line(&value, syntaxes())
+            } else if line.is_context() {
+                let _ = old.highlight_line(&value, syntaxes());
+                new.highlight_line(&value, syntaxes())
+            } else {
+                return Line::from(line.to_string());
+            };
+            match highlighted {
+                Ok(ranges) => Line::from(
+                    ranges
+                        .into_iter()
+                        .map(|(style, text)| span(style, text))
+                        .collect::<Vec<_>>(),
+                ),
+                Err(_) => Line::from(line.value.clone()),
+            }
+        })
+        .collect()
+}
+
+fn span(style: highlighting::Style, text: &str) -> Span<'static> {
+    let highlighting::Color { r, g, b, .. } = style.foreground;
+    Span::styled(
+        text.trim_end_matches('\n').to_string(),
+        Style::new().fg(Color::Rgb(r, g, b)),
+    )
+}
+
+/// pairs each run of removed lines with the run of added lines right after it, line
+/// by line, as (removed, added) indices into [`Hunk::lines`]
+pub fn paired_lines(hunk: &Hunk) -> Vec<(usize, usize)> {
+    let lines = hunk.lines();
+    let mut pairs = vec![];
+    let mut idx = 0;
+    while idx < lines.len() {
+        if !lines[idx].is_removed() {
+            idx += 1;
+            continue;
+        }
+        let removed = idx;
+        while idx < lines.len() && lines[idx].is_removed() {
+            idx += 1;
+        }
+        let added = idx;
+        while idx < lines.len() && lines[idx].is_added() {
+            idx += 1;
+        }
+        pairs.extend((removed..added).zip(added..idx));
+    }
+    pairs
+}
+
+fn changed_ranges(hunk: &Hunk) -> Vec<Ranges> {
+    let lines = hunk.lines();
+    let mut changed = vec![vec![]; lines.len()];
+    for (removed, added) in paired_lines(hunk) {
+        if let Some((old, new)) = word_diff(&lines[removed].value, &lines[added].value) {
+            changed[removed] = old;
+            changed[added] = new;
+        }
+    }
+    changed
+}
+
+/// the byte ranges of `old` and `new` that differ, by word, or `None` if the lines
+/// are too different for that to be useful
+fn word_diff(old: &str, new: &str) -> Option<(Ranges, Ranges)> {
+    let (old_tokens, new_tokens) = (tokens(old), tokens(new));
+    let (n, m) = (old_tokens.len(), new_tokens.len());
+    if n * m > 1_000_000 {
+        return None;
+    }
+    let same = |i: usize, j: usize| old[old_tokens[i].clone()] == new[new_tokens[j].clone()];
+
+    // longest common subsequence of tokens, from the end
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if same(i, j) {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut old_kept, mut new_kept) = (vec![false; n], vec![false; m]);
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if same(i, j) {
+            old_kept[i] = true;
+            new_kept[j] = true;
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    let common: usize = old_tokens
+        .iter()
+        .zip(&old_kept)
+        .filter(|(_, kept)| **kept)
+        .map(|(token, _)| token.len())
+        .sum();
+    if 4 * common < old.len() + new.len() {
+        return None;
+    }
+    Some((
+        unkept(&old_tokens, &old_kept),
+        unkept(&new_tokens, &new_kept),
+    ))
+}
+
+/// splits a line into runs of word characters, runs of whitespace, and single other characters
+fn tokens(line: &str) -> Vec<Range<usize>> {
+    let class = |c: char| {
+        if c.is_alphanumeric() || c == '_' {
+            0
+        } else if c.is_whitespace() {
+            1
+        } else {
+            2
+        }
+    };
+    let mut tokens: Vec<Range<usize>> = vec![];
+    let mut prev = None;
+    for (idx, c) in line.char_indices() {
+        let end = idx + c.len_utf8();
+        match tokens.last_mut() {
+            Some(last) if prev == Some(class(c)) && class(c) != 2 => last.end = end,
+            _ => tokens.push(idx..end),
+        }
+        prev = Some(class(c));
+    }
+    tokens
+}
+
+/// the tokens that weren't kept, with neighbouring ones merged
+fn unkept(tokens: &[Range<usize>], kept: &[bool]) -> Ranges {
+    let mut ranges: Ranges = vec![];
+    for (token, _) in tokens.iter().zip(kept).filter(|(_, kept)| !**kept) {
+        match ranges.last_mut() {
+            Some(last) if last.end == token.start => last.end = token.end,
+            _ => ranges.push(token.clone()),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use unidiff::PatchSet;
+
+    use super::*;
+
+    #[test]
+    fn word_diff_finds_changed_words() {
+        assert_eq!(
+            word_diff("let foo = bar(1);", "let foo_bar = baz(1, 2);"),
+            Some((vec![4..7, 10..13], vec![4..11, 14..17, 19..22]))
+        );
+        assert_eq!(word_diff("fn main() {", "struct Foo;"), None);
+    }
+
+    #[test]
+    fn pairs_removed_lines_with_following_added_lines() {
+        let mut patch = PatchSet::new();
+        patch
+            .parse("--- a/a\n+++ b/a\n@@ -1,5 +1,5 @@\n a\n-b\n-c\n+B\n d\n-e\n+E\n+F\n")
+            .unwrap();
+        assert_eq!(paired_lines(&patch[0][0]), vec![(1, 3), (5, 6)]);
+    }
+}
@@ -5,9 +5,16 @@ use clap::Parser;
 use log::{debug, LevelFilter};
 use simplelog::{Config, WriteLogger};
 mod args;
+mod audit;
+mod auth;
 mod client;
+mod config;
+mod highlight;
 mod models;
+mod review;
 mod server;
+mod store;
+mod tls;
 mod tui;
 
 #[tokio::main]
@@ -32,7 +39,7 @@ async fn main() -> anyhow::Result<()> {
             debug!("Starting client");
             client::Client::from(mode).run().await?;
         }
-        Command::Server => {
+        Command::Server(args) => {
             WriteLogger::init(
                 level_filter,
                 Config::default(),
@@ -40,7 +47,16 @@ async fn main() -> anyhow::Result<()> {
             )?;
 
             debug!("Starting server");
-            server::Server::new().run().await?;
+            server::Server::from(args).run().await?;
+        }
+        Command::Log(args) => {
+            stderrlog::new()
+                .verbosity(verbosity)
+                .module(module_path!())
+                .init()
+                .unwrap();
+
+            audit::Query::try_from(args)?.run()?;
         }
     }
 
@@ -0,0 +1,1376 @@
+use std::{collections::HashMap, fmt::Write as _};
+
+use anyhow::bail;
+use log::warn;
+use regex::Regex;
+use unidiff::{Hunk, Line, PatchSet, PatchedFile};
+
+use crate::models::{
+    file_change::Kind, patch_response, patch_response::Status, FileChange, PatchResponse,
+};
+
+/// identifies something the reviewer decides on: a single hunk, or a file's
+/// file-level change (see [`FileDiff::has_file_change`]) when `hunk` is `None`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ItemId {
+    pub file: usize,
+    pub hunk: Option<usize>,
+}
+
+/// a single file's section of a patch
+#[derive(Clone, Debug)]
+pub struct FileDiff {
+    /// git header lines (`diff --git`, modes, renames, `---`/`+++`), which unidiff drops
+    header: Vec<String>,
+    pub patched: PatchedFile,
+    /// raw `GIT binary patch` (or `Binary files ... differ`) section
+    binary: Option<String>,
+    pub change: FileChange,
+}
+
+impl FileDiff {
+    /// the file's path, or where it was for deletions
+    pub fn path(&self) -> &str {
+        match self.change.kind() {
+            Kind::Deleted => &self.change.old_path,
+            _ => &self.change.new_path,
+        }
+    }
+
+    /// whether the file has changes beyond its hunks that need their own decision
+    pub fn has_file_change(&self) -> bool {
+        self.is_whole_file()
+            || self.mode_changed()
+            || matches!(self.change.kind(), Kind::Renamed | Kind::Copied)
+    }
+
+    /// whether the file can only be accepted or rejected as a whole, not hunk by hunk
+    pub fn is_whole_file(&self) -> bool {
+        self.change.binary
+            || matches!(self.change.kind(), Kind::Deleted | Kind::TypeChanged)
+            || self.patched.is_empty()
+    }
+
+    /// a rename or copy that doesn't touch the file's contents
+    pub fn is_pure_move(&self) -> bool {
+        matches!(self.change.kind(), Kind::Renamed | Kind::Copied)
+            && self.patched.is_empty()
+            && self.binary.is_none()
+            && !self.mode_changed()
+    }
+
+    /// how similar a renamed or copied file is to its source, in percent
+    pub fn similarity(&self) -> Option<u32> {
+        self.header.iter().find_map(|line| {
+            line.strip_prefix("similarity index ")?
+                .trim_end_matches('%')
+                .parse()
+                .ok()
+        })
+    }
+
+    pub fn mode_changed(&self) -> bool {
+        self.change.old_mode != 0
+            && self.change.new_mode != 0
+            && self.change.old_mode != self.change.new_mode
+    }
+
+    /// renders the file, keeping the file-level change only if `file_level` is set
+    fn write(&self, out: &mut String, file_level: bool, hunks: &[&Hunk], target_offsets: &[isize]) {
+        if file_level || !self.has_file_change() {
+            for line in &self.header {
+                // a header without hunks can't reference any content
+                let content_line = ["index ", "--- ", "+++ "]
+                    .iter()
+                    .any(|prefix| line.starts_with(prefix));
+                if hunks.is_empty() && !self.is_whole_file() && content_line {
+                    continue;
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+        } else {
+            // drop the mode change / rename, applying the hunks to the file as it was
+            let old = &self.change.old_path;
+            let _ = write!(
+                out,
+                "diff --git a/{old} b/{old}\n--- a/{old}\n+++ b/{old}\n"
+            );
+        }
+
+        for (hunk, offset) in hunks.iter().zip(target_offsets) {
+            write_hunk(out, hunk, (hunk.target_start as isize - offset) as usize);
+        }
+        if file_level {
+            if let Some(binary) = &self.binary {
+                out.push_str(binary);
+            }
+        }
+    }
+}
+
+/// which hunk of the submitted patch a hunk is, and what the reviewer did to it
+#[derive(Clone, Copy, Debug, Default)]
+struct Origin {
+    hunk: usize,
+    /// the hunk's new side in the submitted patch, see [`new_side`]
+    target: (usize, usize),
+    split: bool,
+    edited: bool,
+    lines_selected: bool,
+}
+
+/// a patch under review, tracking the reviewer's decision for every hunk and file-level change
+#[derive(Clone, Debug)]
+pub struct Review {
+    files: Vec<FileDiff>,
+    /// everything to decide on, in display order
+    items: Vec<(ItemId, Option<Status>)>,
+    /// where each item is in `items`
+    positions: HashMap<ItemId, usize>,
+    /// each file's hunks' [`Origin`]s, indexed like its hunks
+    origins: Vec<Vec<Origin>>,
+    /// index into `items` of the one awaiting a decision
+    cursor: Option<usize>,
+}
+
+impl Review {
+    /// `changes` describes each `diff --git` section of `raw`, and is derived
+    /// from the git headers if the client didn't send (matching) ones
+    pub fn new(raw: &str, changes: &[FileChange]) -> anyhow::Result<Self> {
+        let files = parse_files(raw, changes)?;
+
+        let mut items = vec![];
+        for (file_idx, file) in files.iter().enumerate() {
+            if file.has_file_change() {
+                items.push((
+                    ItemId {
+                        file: file_idx,
+                        hunk: None,
+                    },
+                    None,
+                ));
+            }
+            if !file.is_whole_file() {
+                for hunk_idx in 0..file.patched.hunks().len() {
+                    items.push((
+                        ItemId {
+                            file: file_idx,
+                            hunk: Some(hunk_idx),
+                        },
+                        None,
+                    ));
+                }
+            }
+        }
+
+        let origins = files
+            .iter()
+            .map(|file| {
+                file.patched
+                    .hunks()
+                    .iter()
+                    .enumerate()
+                    .map(|(hunk, h)| Origin {
+                        hunk,
+                        target: new_side(h),
+                        ..Default::default()
+                    })
+                    .collect()
+            })
+            .collect();
+        let mut review = Review {
+            files,
+            items,
+            positions: HashMap::new(),
+            origins,
+            cursor: None,
+        };
+        review.index_items();
+        review.cursor = review.next_undecided(0);
+        Ok(review)
+    }
+
+    pub fn files(&self) -> &[FileDiff] {
+        &self.files
+    }
+
+    /// the item currently awaiting a decision
+    pub fn cursor(&self) -> Option<ItemId> {
+        self.cursor.map(|idx| self.items[idx].0)
+    }
+
+    /// the items belonging to a file, in order
+    pub fn items_of(&self, file: usize) -> impl Iterator<Item = ItemId> + '_ {
+        // items are in file order
+        let start = self.items.partition_point(|(id, _)| id.file < file);
+        self.items[start..]
+            .iter()
+            .map(|(id, _)| *id)
+            .take_while(move |id| id.file == file)
+    }
+
+    pub fn decision(&self, id: ItemId) -> Option<Status> {
+        // a copy's hunks would otherwise be applied to the file it was copied from
+        if id.hunk.is_some() && self.copy_rejected(id.file) {
+            return Some(Status::Rejected);
+        }
+        self.positions.get(&id).and_then(|&idx| self.items[idx].1)
+    }
+
+    fn copy_rejected(&self, file: usize) -> bool {
+        self.files[file].change.kind() == Kind::Copied
+            && self
+                .positions
+                .get(&ItemId { file, hunk: None })
+                .is_some_and(|&idx| self.items[idx].1 == Some(Status::Rejected))
+    }
+
+    /// 1-based position of the cursor alongside the total number of items
+    pub fn progress(&self) -> (usize, usize) {
+        let total = self.items.len();
+        let position = self.cursor.map_or(total, |idx| idx + 1);
+        (position, total)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.cursor.is_none()
+    }
+
+    /// records a decision for the current item and moves on to the next undecided one
+    pub fn decide(&mut self, status: Status) {
+        if let Some(cursor) = self.cursor {
+            self.items[cursor].1 = Some(status);
+            let file = self.items[cursor].0.file;
+            if self.items[cursor].0.hunk.is_none() && self.copy_rejected(file) {
+                for (id, decision) in &mut self.items {
+                    if id.file == file {
+                        *decision = Some(Status::Rejected);
+                    }
+                }
+            }
+            self.cursor = self.next_undecided(cursor);
+        }
+    }
+
+    /// records a decision for every item that hasn't been decided yet
+    pub fn decide_rest(&mut self, status: Status) {
+        for (_, decision) in &mut self.items {
+            decision.get_or_insert(status);
+        }
+        self.cursor = None;
+    }
+
+    /// moves the cursor `step` items forward (or back), whether they're decided or not
+    pub fn move_cursor(&mut self, step: isize) {
+        let Some(cursor) = self.cursor else {
+            return;
+        };
+        let last = self.items.len() as isize - 1;
+        self.cursor = Some((cursor as isize + step).clamp(0, last) as usize);
+    }
+
+    /// moves the cursor to the first item of the file `step` files forward (or back)
+    pub fn move_to_file(&mut self, step: isize) {
+        let Some(cursor) = self.cursor() else {
+            return;
+        };
+        let last = self.files.len() as isize - 1;
+        let file = (cursor.file as isize + step).clamp(0, last) as usize;
+        self.cursor = self.items.iter().position(|(id, _)| id.file == file);
+    }
+
+    /// a hunk's new side as the client has it, which it reads context from, however
+    /// the reviewer has changed the hunk (or the ones before it) since
+    pub fn context_range(&self, file: usize, hunk: usize) -> (usize, usize) {
+        self.origins[file][hunk].target
+    }
+
+    /// moves the cursor to the first undecided item of the nearest file after `file`
+    /// (or else before it) that has one, returning whether there was one
+    pub fn move_past_file(&mut self, file: usize) -> bool {
+        let undecided = |(id, decision): &(ItemId, Option<Status>)| {
+            (id.file != file && decision.is_none()).then_some(id.file)
+        };
+        let after = self.items.iter().filter_map(undecided).find(|f| *f > file);
+        let before = self
+            .items
+            .iter()
+            .rev()
+            .filter_map(undecided)
+            .find(|f| *f < file);
+        let Some(target) = after.or(before) else {
+            return false;
+        };
+        self.cursor = self
+            .items
+            .iter()
+            .position(|(id, decision)| id.file == target && decision.is_none());
+        true
+    }
+
+    pub fn response(&self) -> PatchResponse {
+        let mut hunks = vec![];
+        let mut files = vec![];
+        for (id, _) in &self.items {
+            let status = self.decision(*id).unwrap_or(Status::Unknown).into();
+            match id.hunk {
+                Some(hunk) => {
+                    let origin = self.origins[id.file][hunk];
+                    hunks.push(patch_response::Hunk {
+                        file: id.file as u32,
+                        hunk: hunk as u32,
+                        status,
+                        original: origin.hunk as u32,
+                        split: origin.split,
+                        edited: origin.edited,
+                        lines_selected: origin.lines_selected,
+                    })
+                }
+                None => files.push(patch_response::File {
+                    file: id.file as u32,
+                    status,
+                }),
+            }
+        }
+        let accepted = self
+            .items
+            .iter()
+            .filter(|(id, _)| self.decision(*id) == Some(Status::Accepted))
+            .count();
+
+        let status = if accepted == self.items.len() {
+            Status::Accepted
+        } else if accepted == 0 {
+            Status::Rejected
+        } else {
+            Status::Partial
+        };
+
+        PatchResponse {
+            status: status.into(),
+            hunks,
+            files,
+            patch: self.accepted_patch(),
+            commit_message: None,
+        }
+    }
+
+    /// renders a unified diff containing only what was accepted
+    fn accepted_patch(&self) -> String {
+        let mut patch = String::new();
+        for (file_idx, file) in self.files.iter().enumerate() {
+            let accepted = |hunk| {
+                self.decision(ItemId {
+                    file: file_idx,
+                    hunk,
+                }) == Some(Status::Accepted)
+            };
+            let file_level = file.has_file_change() && accepted(None);
+            if file.is_whole_file() {
+                if file_level {
+                    let hunks: Vec<_> = file.patched.hunks().iter().collect();
+                    file.write(&mut patch, true, &hunks, &vec![0; hunks.len()]);
+                }
+                continue;
+            }
+
+            // skipping a hunk shifts where every later hunk lands in the target file
+            let mut skipped_offset = 0isize;
+            let mut hunks = vec![];
+            let mut offsets = vec![];
+            for (hunk_idx, hunk) in file.patched.hunks().iter().enumerate() {
+                if accepted(Some(hunk_idx)) {
+                    // split hunks share the context between them, which `git apply`
+                    // won't take twice
+                    match hunks.last_mut() {
+                        Some(last) if overlaps(last, hunk) => *last = merge_hunks(last, hunk),
+                        _ => {
+                            hunks.push(hunk.clone());
+                            offsets.push(skipped_offset);
+                        }
+                    }
+                } else {
+                    skipped_offset += hunk.target_length as isize - hunk.source_length as isize;
+                }
+            }
+            if file_level || !hunks.is_empty() {
+                let hunks: Vec<_> = hunks.iter().collect();
+                file.write(&mut patch, file_level, &hunks, &offsets);
+            }
+        }
+        patch
+    }
+
+    /// replaces a hunk with the reviewer's edit of it (see [`edit_template`]), which
+    /// has to leave the hunk's old side as it was so that it still applies
+    pub fn edit_hunk(&mut self, file: usize, hunk: usize, edited: &str) -> anyhow::Result<()> {
+        let original = &self.files[file].patched.hunks()[hunk];
+        let mut lines = vec![];
+        for line in edited.lines() {
+            if line.starts_with('#') || line.starts_with("@@") {
+                continue;
+            }
+            // editors tend to strip the space off blank context lines
+            let (line_type, value) = match line.chars().next() {
+                None => (" ", ""),
+                Some(' ' | '+' | '-' | '\\') => line.split_at(1),
+                Some(_) => bail!("`{line}` doesn't start with ' ', '+' or '-'"),
+            };
+            lines.push((line_type, value));
+        }
+        if lines.is_empty() {
+            bail!("the edited hunk is empty, so it was left as it was");
+        }
+        if !lines.iter().any(|(t, _)| matches!(*t, "+" | "-")) {
+            bail!("the edited hunk doesn't change anything, reject it instead");
+        }
+        let old_side = |lines: Vec<(&str, &str)>| {
+            lines
+                .into_iter()
+                .filter(|(t, _)| matches!(*t, " " | "-"))
+                .map(|(_, v)| v.to_string())
+                .collect::<Vec<_>>()
+        };
+        let original_lines = original
+            .lines()
+            .iter()
+            .map(|l| (l.line_type.as_str(), l.value.as_str()))
+            .collect();
+        if old_side(lines.clone()) != old_side(original_lines) {
+            bail!("the edited hunk changes lines that aren't being added, so it wouldn't apply");
+        }
+
+        let edited = build_hunk(
+            original.source_start,
+            original.target_start,
+            &original.section_header,
+            lines,
+        );
+        self.replace_hunk(file, hunk, edited);
+        self.origins[file][hunk].edited = true;
+        Ok(())
+    }
+
+    /// narrows a hunk down to the changed lines that `keep` is set for (indexed like
+    /// its lines): removals that aren't kept stay as context, and additions are dropped
+    pub fn select_lines(&mut self, file: usize, hunk: usize, keep: &[bool]) -> anyhow::Result<()> {
+        let original = &self.files[file].patched.hunks()[hunk];
+        let mut lines = vec![];
+        let mut dropped = false;
+        for (idx, line) in original.lines().iter().enumerate() {
+            let kept = keep.get(idx).copied().unwrap_or(true);
+            let line_type = match line.line_type.as_str() {
+                "+" if !kept => {
+                    dropped = true;
+                    continue;
+                }
+                "-" if !kept => " ",
+                // a "\ No newline at end of file" marker goes with the line before it
+                "\\" if dropped => continue,
+                line_type => line_type,
+            };
+            dropped = false;
+            lines.push((line_type, line.value.as_str()));
+        }
+        if !lines.iter().any(|(t, _)| matches!(*t, "+" | "-")) {
+            bail!("no changed lines are selected");
+        }
+        let selected = build_hunk(
+            original.source_start,
+            original.target_start,
+            &original.section_header,
+            lines,
+        );
+        self.replace_hunk(file, hunk, selected);
+        self.origins[file][hunk].lines_selected = true;
+        Ok(())
+    }
+
+    /// swaps a hunk for one with the same old side, moving the new side of later
+    /// hunks to match
+    fn replace_hunk(&mut self, file: usize, hunk: usize, replaced: Hunk) {
+        let original = &self.files[file].patched.hunks()[hunk];
+        let shift = replaced.target_length as isize - original.target_length as isize;
+        let hunks = self.files[file].patched.hunks_mut();
+        hunks[hunk] = replaced;
+        for later in &mut hunks[hunk + 1..] {
+            shift_target(later, shift);
+        }
+    }
+
+    /// splits a hunk into one hunk per run of changes, like git's `s`, returning how
+    /// many it became
+    ///
+    /// the context between two runs ends one hunk and starts the next, and each new
+    /// hunk keeps the original's decision, if it had one.
+    pub fn split_hunk(&mut self, file: usize, hunk: usize) -> usize {
+        let original = &self.files[file].patched.hunks()[hunk];
+        let lines = original.lines();
+        // a "\ No newline at end of file" marker goes with the line before it
+        let mut changed = vec![false; lines.len()];
+        for (idx, line) in lines.iter().enumerate() {
+            changed[idx] = line.is_added()
+                || line.is_removed()
+                || (!line.is_context() && idx > 0 && changed[idx - 1]);
+        }
+        let mut runs = vec![];
+        for idx in 0..lines.len() {
+            if changed[idx] && (idx == 0 || !changed[idx - 1]) {
+                runs.push(idx..idx);
+            }
+            if let Some(run) = runs.last_mut().filter(|_| changed[idx]) {
+                run.end = idx + 1;
+            }
+        }
+        if runs.len() < 2 {
+            return 1;
+        }
+
+        let parts: Vec<Hunk> = (0..runs.len())
+            .map(|run| {
+                let start = if run == 0 { 0 } else { runs[run - 1].end };
+                let end = runs.get(run + 1).map_or(lines.len(), |next| next.start);
+                let part = &lines[start..end];
+                let first_no = |line_no: fn(&Line) -> Option<usize>| {
+                    part.iter().find_map(line_no).unwrap_or_default()
+                };
+                build_hunk(
+                    first_no(|l| l.source_line_no),
+                    first_no(|l| l.target_line_no),
+                    &original.section_header,
+                    part.iter()
+                        .map(|l| (l.line_type.as_str(), l.value.as_str())),
+                )
+            })
+            .collect();
+        let count = parts.len();
+        let origin = self.origins[file][hunk];
+        let shown = new_side(original).0;
+        let origins: Vec<_> = parts
+            .iter()
+            .map(|part| {
+                let (first, len) = new_side(part);
+                Origin {
+                    split: true,
+                    target: (origin.target.0 + first - shown, len),
+                    ..origin
+                }
+            })
+            .collect();
+
+        let patched = &mut self.files[file].patched;
+        let mut hunks = patched.hunks().to_vec();
+        hunks.splice(hunk..=hunk, parts);
+        let (source_timestamp, target_timestamp) = (
+            patched.source_timestamp.take(),
+            patched.target_timestamp.take(),
+        );
+        *patched = PatchedFile::with_hunks(
+            patched.source_file.clone(),
+            patched.target_file.clone(),
+            hunks,
+        );
+        patched.source_timestamp = source_timestamp;
+        patched.target_timestamp = target_timestamp;
+
+        let split = ItemId {
+            file,
+            hunk: Some(hunk),
+        };
+        self.origins[file].splice(hunk..=hunk, origins);
+        let idx = self.positions[&split];
+        let decision = self.items[idx].1;
+        for (id, _) in &mut self.items {
+            match &mut id.hunk {
+                Some(later) if id.file == file && *later > hunk => *later += count - 1,
+                _ => {}
+            }
+        }
+        let parts = (hunk..hunk + count).map(|hunk| {
+            (
+                ItemId {
+                    file,
+                    hunk: Some(hunk),
+                },
+                decision,
+            )
+        });
+        self.items.splice(idx..=idx, parts);
+        self.index_items();
+        if let Some(cursor) = self.cursor.filter(|cursor| *cursor > idx) {
+            self.cursor = Some(cursor + count - 1);
+        }
+        count
+    }
+
+    fn index_items(&mut self) {
+        self.positions = self
+            .items
+            .iter()
+            .enumerate()
+            .map(|(idx, (id, _))| (*id, idx))
+            .collect();
+    }
+
+    /// finds the first undecided item at or after `from`, wrapping around to the start
+    fn next_undecided(&self, from: usize) -> Option<usize> {
+        (from..self.items.len())
+            .chain(0..from)
+            .find(|idx| self.items[*idx].1.is_none())
+    }
+}
+
+/// what the reviewer edits a hunk as: its lines, with instructions in comments
+pub fn edit_template(hunk: &Hunk) -> String {
+    let mut out = String::from("# Manual hunk edit mode -- see bottom for a quick guide.\n");
+    write_hunk(&mut out, hunk, hunk.target_start);
+    out.push_str(
+        "\
+# ---
+# To remove '-' lines, make them ' ' lines (context).
+# To remove '+' lines, delete them.
+# Lines starting with # will be removed.
+# If the edited hunk changes lines that are there now (context or '-' lines),
+# it won't apply and is left as it was. Empty the file to cancel the edit.
+",
+    );
+    out
+}
+
+/// builds a hunk out of `(line_type, value)` pairs, counting its lengths and numbering
+/// its lines from `source_start` and `target_start`
+fn build_hunk<'a>(
+    source_start: usize,
+    target_start: usize,
+    section_header: &str,
+    lines: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Hunk {
+    let lines: Vec<_> = lines.into_iter().collect();
+    let count = |types: &[&str]| lines.iter().filter(|(t, _)| types.contains(t)).count();
+    let mut hunk = Hunk::new(
+        source_start,
+        count(&[" ", "-"]),
+        target_start,
+        count(&[" ", "+"]),
+        section_header,
+    );
+    let (mut source_line_no, mut target_line_no) = (source_start, target_start);
+    for (line_type, value) in lines {
+        let mut line = Line::new(value, line_type);
+        line.source_line_no = None;
+        line.target_line_no = None;
+        if matches!(line_type, " " | "-") {
+            line.source_line_no = Some(source_line_no);
+            source_line_no += 1;
+        }
+        if matches!(line_type, " " | "+") {
+            line.target_line_no = Some(target_line_no);
+            target_line_no += 1;
+        }
+        hunk.append(line);
+    }
+    hunk
+}
+
+/// whether `next` starts within `hunk`'s old side
+fn overlaps(hunk: &Hunk, next: &Hunk) -> bool {
+    next.source_start < hunk.source_start + hunk.source_length
+}
+
+/// joins two overlapping hunks into one, like git's `merge_hunks`
+fn merge_hunks(hunk: &Hunk, next: &Hunk) -> Hunk {
+    let end = hunk.source_start + hunk.source_length;
+    let rest = next
+        .lines()
+        .iter()
+        .skip_while(|l| l.is_context() && l.source_line_no.is_some_and(|n| n < end));
+    build_hunk(
+        hunk.source_start,
+        hunk.target_start,
+        &hunk.section_header,
+        hunk.lines()
+            .iter()
+            .chain(rest)
+            .map(|l| (l.line_type.as_str(), l.value.as_str())),
+    )
+}
+
+/// the first line of a hunk's new side, and how many lines it has
+pub fn new_side(hunk: &Hunk) -> (usize, usize) {
+    // a side without any lines is numbered with the line it comes after
+    let first = hunk.target_start + usize::from(hunk.target_length == 0);
+    (first, hunk.target_length)
+}
+
+/// moves a hunk's new side `shift` lines down (or up), after an earlier hunk changed size
+fn shift_target(hunk: &mut Hunk, shift: isize) {
+    hunk.target_start = (hunk.target_start as isize + shift) as usize;
+    for line in hunk.lines_mut() {
+        line.target_line_no = line.target_line_no.map(|n| (n as isize + shift) as usize);
+    }
+}
+
+fn write_hunk(out: &mut String, hunk: &Hunk, target_start: usize) {
+    let _ = write!(
+        out,
+        "@@ -{},{} +{},{} @@",
+        hunk.source_start, hunk.source_length, target_start, hunk.target_length
+    );
+    if !hunk.section_header.is_empty() {
+        out.push(' ');
+        out.push_str(&hunk.section_header);
+    }
+    out.push('\n');
+    for line in hunk.lines() {
+        let _ = writeln!(out, "{}", line);
+    }
+}
+
+/// splits a patch into one [`FileDiff`] per file
+fn parse_files(raw: &str, changes: &[FileChange]) -> anyhow::Result<Vec<FileDiff>> {
+    let raw = normalize_hunk_headers(raw);
+    let sections = split_sections(&raw);
+
+    // a plain unified diff, without any git headers
+    if sections.is_empty() {
+        let mut patch_set = parse_patch_set(&raw)?;
+        restore_eof_markers(&raw, &mut patch_set);
+        return Ok(patch_set
+            .into_iter()
+            .map(|patched| {
+                let header = synthesize_git_header(&patched);
+                let change = change_from_header(&header, &patched, false);
+                FileDiff {
+                    header,
+                    patched,
+                    binary: None,
+                    change,
+                }
+            })
+            .collect());
+    }
+
+    let aligned = changes.len() == sections.len();
+    if !changes.is_empty() && !aligned {
+        warn!(
+            "got {} file changes for {} files, ignoring them",
+            changes.len(),
+            sections.len()
+        );
+    }
+    sections
+        .into_iter()
+        .enumerate()
+        .map(|(idx, section)| parse_section(section, aligned.then(|| changes[idx].clone())))
+        .collect()
+}
+
+fn parse_patch_set(raw: &str) -> anyhow::Result<PatchSet> {
+    // unidiff panics on a `+++` line that comes before any `---` line
+    for line in raw.lines() {
+        if line.starts_with("--- ") {
+            break;
+        }
+        if line.starts_with("+++ ") {
+            bail!("`{line}` has no `---` line before it");
+        }
+    }
+    Ok(raw.parse()?)
+}
+
+/// splits a patch at each `diff --git` line, dropping anything before the first
+fn split_sections(raw: &str) -> Vec<&str> {
+    let mut starts = vec![];
+    let mut offset = 0;
+    for line in raw.split_inclusive('\n') {
+        if line.starts_with("diff --git ") {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    starts
+        .iter()
+        .enumerate()
+        .map(|(idx, start)| &raw[*start..starts.get(idx + 1).copied().unwrap_or(raw.len())])
+        .collect()
+}
+
+fn parse_section(section: &str, change: Option<FileChange>) -> anyhow::Result<FileDiff> {
+    let mut header = vec![];
+    let mut binary = None;
+    let mut offset = 0;
+    for line in section.split_inclusive('\n') {
+        let trimmed = line.trim_end_matches('\n');
+        if trimmed.starts_with("@@ ") {
+            break;
+        }
+        if trimmed.starts_with("GIT binary patch") || trimmed.starts_with("Binary files ") {
+            binary = Some(section[offset..].to_string());
+            break;
+        }
+        header.push(trimmed.to_string());
+        offset += line.len();
+    }
+
+    let mut patch_set = parse_patch_set(section)?;
+    restore_eof_markers(section, &mut patch_set);
+    let patched = match patch_set.into_iter().next() {
+        Some(patched) => patched,
+        // nothing but a header (e.g. binary files, mode changes and pure renames)
+        None => {
+            let (old, new) = git_header_paths(&header[0]);
+            PatchedFile::new(format!("a/{old}"), format!("b/{new}"))
+        }
+    };
+    let change = change.unwrap_or_else(|| change_from_header(&header, &patched, binary.is_some()));
+
+    Ok(FileDiff {
+        header,
+        patched,
+        binary,
+        change,
+    })
+}
+
+/// the old and new paths of a `diff --git a/old b/new` line
+fn git_header_paths(line: &str) -> (String, String) {
+    let paths = line.trim_start_matches("diff --git ");
+    let (old, new) = paths.split_once(" b/").unwrap_or((paths, paths));
+    (
+        old.trim_start_matches("a/").to_string(),
+        new.trim_start_matches("b/").to_string(),
+    )
+}
+
+/// works out a file's [`FileChange`] from its git header
+fn change_from_header(header: &[String], patched: &PatchedFile, binary: bool) -> FileChange {
+    let (mut old_path, mut new_path) = match header.first() {
+        Some(line) if line.starts_with("diff --git ") => git_header_paths(line),
+        _ => (patched.path(), patched.path()),
+    };
+    let mode = |m: &str| u32::from_str_radix(m.trim(), 8).unwrap_or_default();
+
+    let mut kind = match (patched.source_file.as_str(), patched.target_file.as_str()) {
+        ("/dev/null", _) => Kind::Added,
+        (_, "/dev/null") => Kind::Deleted,
+        _ => Kind::Modified,
+    };
+    let (mut old_mode, mut new_mode) = (0, 0);
+    for line in header {
+        if let Some(m) = line.strip_prefix("new file mode ") {
+            kind = Kind::Added;
+            new_mode = mode(m);
+        } else if let Some(m) = line.strip_prefix("deleted file mode ") {
+            kind = Kind::Deleted;
+            old_mode = mode(m);
+        } else if let Some(m) = line.strip_prefix("old mode ") {
+            old_mode = mode(m);
+        } else if let Some(m) = line.strip_prefix("new mode ") {
+            new_mode = mode(m);
+        } else if let Some(path) = line.strip_prefix("rename from ") {
+            kind = Kind::Renamed;
+            old_path = path.to_string();
+        } else if let Some(path) = line.strip_prefix("rename to ") {
+            new_path = path.to_string();
+        } else if let Some(path) = line.strip_prefix("copy from ") {
+            kind = Kind::Copied;
+            old_path = path.to_string();
+        } else if let Some(path) = line.strip_prefix("copy to ") {
+            new_path = path.to_string();
+        } else if let Some(index) = line.strip_prefix("index ") {
+            // `index <old>..<new> <mode>` when the mode didn't change
+            if let Some((_, m)) = index.split_once(' ') {
+                old_mode = mode(m);
+                new_mode = mode(m);
+            }
+        }
+    }
+
+    FileChange {
+        kind: kind.into(),
+        old_path,
+        new_path,
+        old_mode,
+        new_mode,
+        binary,
+    }
+}
+
+/// builds git header lines for a file from a plain unified diff
+fn synthesize_git_header(patched: &PatchedFile) -> Vec<String> {
+    let path = patched.path();
+    let mut header = vec![format!("diff --git a/{path} b/{path}")];
+    if patched.source_file == "/dev/null" {
+        header.push("new file mode 100644".to_string());
+    } else if patched.target_file == "/dev/null" {
+        header.push("deleted file mode 100644".to_string());
+    }
+    header.push(format!("--- {}", patched.source_file));
+    header.push(format!("+++ {}", patched.target_file));
+    header
+}
+
+/// spells out hunk lengths that git omits when they're 1 (`@@ -3 +3 @@`)
+///
+/// unidiff treats a missing length as 0, which makes it swallow the rest of the diff.
+fn normalize_hunk_headers(raw: &str) -> String {
+    let re = Regex::new(r"(?m)^@@ -(\d+)(,\d+)? \+(\d+)(,\d+)? @@").unwrap();
+    re.replace_all(raw, |caps: &regex::Captures| {
+        format!(
+            "@@ -{}{} +{}{} @@",
+            &caps[1],
+            caps.get(2).map_or(",1", |m| m.as_str()),
+            &caps[3],
+            caps.get(4).map_or(",1", |m| m.as_str()),
+        )
+    })
+    .into_owned()
+}
+
+/// re-attaches `\ No newline at end of file` markers that trail a hunk
+///
+/// unidiff stops reading a hunk once it has seen every line its header promised,
+/// so the marker is lost and applying the patch would add a trailing newline.
+fn restore_eof_markers(raw: &str, patch_set: &mut PatchSet) {
+    let raw_lines: Vec<_> = raw.split('\n').collect();
+    for file in patch_set.files_mut() {
+        for hunk in file.hunks_mut() {
+            // `diff_line_no` is 1-based, so it's also the index of the line after it
+            let Some(next) = hunk.lines().last().map(|l| l.diff_line_no) else {
+                continue;
+            };
+            if let Some(marker) = raw_lines.get(next) {
+                if let Some(value) = marker.strip_prefix('\\') {
+                    let mut line = Line::new(value, "\\");
+                    line.diff_line_no = next + 1;
+                    line.source_line_no = None;
+                    line.target_line_no = None;
+                    hunk.append(line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "\
+diff --git a/foo.txt b/foo.txt
+index 1111111..2222222 100644
+--- a/foo.txt
++++ b/foo.txt
+@@ -1,3 +1,4 @@
+ one
++two
+ three
+ four
+@@ -10,3 +11,3 @@ fn main
+ ten
+-eleven
++ELEVEN
+ twelve
+diff --git a/new.txt b/new.txt
+new file mode 100755
+index 0000000..3333333
+--- /dev/null
++++ b/new.txt
+@@ -0,0 +1,2 @@
++hello
++world
+";
+
+    #[test]
+    fn walks_hunks_in_order() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        assert_eq!(review.progress(), (1, 3));
+        review.decide(Status::Rejected);
+        assert_eq!(
+            review.cursor(),
+            Some(ItemId {
+                file: 0,
+                hunk: Some(1)
+            })
+        );
+        review.decide(Status::Accepted);
+        assert_eq!(review.progress(), (3, 3));
+        review.decide(Status::Accepted);
+        assert!(review.is_done());
+    }
+
+    #[test]
+    fn jumps_between_files_and_hunks() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        let at = |file, hunk| {
+            Some(ItemId {
+                file,
+                hunk: Some(hunk),
+            })
+        };
+        review.move_to_file(1);
+        assert_eq!(review.cursor(), at(1, 0));
+        review.move_cursor(-1);
+        assert_eq!(review.cursor(), at(0, 1));
+        review.decide(Status::Accepted);
+        assert_eq!(review.cursor(), at(1, 0));
+        review.decide(Status::Rejected);
+        // skipped hunks are come back to at the end
+        assert_eq!(review.cursor(), at(0, 0));
+        review.move_cursor(-5);
+        assert_eq!(review.cursor(), at(0, 0));
+        review.decide(Status::Accepted);
+        assert!(review.is_done());
+    }
+
+    #[test]
+    fn accepting_everything_reproduces_the_patch() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        review.decide_rest(Status::Accepted);
+        let response = review.response();
+        assert_eq!(response.status(), Status::Accepted);
+        assert_eq!(response.patch, DIFF);
+    }
+
+    #[test]
+    fn rejected_hunks_are_dropped_and_offsets_fixed() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        review.decide(Status::Rejected);
+        review.decide(Status::Accepted);
+        review.decide(Status::Rejected);
+        let response = review.response();
+        assert_eq!(response.status(), Status::Partial);
+        assert_eq!(
+            response.patch,
+            "\
+diff --git a/foo.txt b/foo.txt
+index 1111111..2222222 100644
+--- a/foo.txt
++++ b/foo.txt
+@@ -10,3 +10,3 @@ fn main
+ ten
+-eleven
++ELEVEN
+ twelve
+"
+        );
+    }
+
+    #[test]
+    fn edited_hunks_replace_the_original() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        let edited = " one\n+two\n+2\n three\n four\n";
+        review.edit_hunk(0, 0, edited).unwrap();
+        assert_eq!(review.files()[0].patched.hunks()[1].target_start, 12);
+
+        // context has to stay as it is for the hunk to apply
+        let broken = " ten\n-eleven\n+ELEVEN\n twelve!\n";
+        assert!(review.edit_hunk(0, 1, broken).is_err());
+        assert!(review.edit_hunk(0, 1, "# nothing left\n").is_err());
+
+        review.decide_rest(Status::Accepted);
+        let patch = review.response().patch;
+        assert!(patch.contains("@@ -1,3 +1,5 @@\n one\n+two\n+2\n three\n four\n"));
+        assert!(patch.contains("@@ -10,3 +12,3 @@ fn main\n"));
+    }
+
+    #[test]
+    fn split_hunks_are_decided_separately() {
+        let diff = "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,6 +1,6 @@ fn main
+ a
+-b
++B
+ c
+ d
+-e
++E
+ f
+@@ -20,2 +20,3 @@
+ t
++u
+ v
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        assert_eq!(review.split_hunk(0, 0), 2);
+        assert_eq!(review.progress(), (1, 3));
+        assert_eq!(review.split_hunk(0, 2), 1);
+
+        review.decide(Status::Rejected);
+        review.decide(Status::Accepted);
+        review.decide(Status::Accepted);
+        assert_eq!(
+            review.response().patch,
+            "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -3,4 +3,4 @@ fn main
+ c
+ d
+-e
++E
+ f
+@@ -20,2 +20,3 @@
+ t
++u
+ v
+"
+        );
+    }
+
+    #[test]
+    fn accepted_parts_of_a_split_hunk_are_joined() {
+        let diff = "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,6 +1,6 @@ fn main
+ a
+-b
++B
+ c
+ d
+-e
++E
+ f
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        assert_eq!(review.split_hunk(0, 0), 2);
+        review.decide_rest(Status::Accepted);
+        assert_eq!(review.response().patch, diff);
+    }
+
+    #[test]
+    fn moving_past_a_file_skips_decided_items() {
+        let mut review = Review::new(FILE_CHANGES, &[]).unwrap();
+        // run.sh's mode change and hunk, logo.png, old.txt
+        review.decide(Status::Accepted);
+        review.move_cursor(1);
+        review.decide(Status::Accepted);
+        review.move_cursor(-2);
+        assert_eq!(review.cursor().unwrap().file, 0);
+        assert!(review.move_past_file(0));
+        assert_eq!(review.cursor().unwrap().file, 2);
+        review.decide(Status::Accepted);
+        assert!(!review.move_past_file(0));
+        assert_eq!(review.cursor().unwrap().file, 0);
+    }
+
+    #[test]
+    fn context_is_read_around_the_hunks_as_submitted() {
+        let diff = "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,6 +1,6 @@
+ a
+-b
++B
+ c
+ d
+-e
++E
+ f
+@@ -20,2 +20,3 @@
+ t
++u
+ v
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        review
+            .edit_hunk(0, 0, " a\n-b\n+B\n+B2\n c\n d\n-e\n+E\n f\n")
+            .unwrap();
+        assert_eq!(review.files()[0].patched.hunks()[1].target_start, 21);
+        assert_eq!(review.context_range(0, 1), (20, 3));
+
+        let mut review = Review::new(diff, &[]).unwrap();
+        review.split_hunk(0, 0);
+        assert_eq!(review.context_range(0, 0), (1, 4));
+        assert_eq!(review.context_range(0, 1), (3, 4));
+        assert_eq!(review.context_range(0, 2), (20, 3));
+    }
+
+    #[test]
+    fn malformed_patches_are_refused() {
+        assert!(Review::new("+++ b/f\n@@ -1 +1 @@\n-x\n+y\n", &[]).is_err());
+    }
+
+    #[test]
+    fn unselected_lines_are_left_out() {
+        let mut review = Review::new(DIFF, &[]).unwrap();
+        let hunk = " ten\n-eleven\n+ELEVEN\n+eleven and a half\n twelve\n";
+        review.edit_hunk(0, 1, hunk).unwrap();
+        review
+            .select_lines(0, 1, &[true, false, false, true, true])
+            .unwrap();
+        assert!(review.select_lines(0, 0, &[true, false]).is_err());
+
+        review.decide_rest(Status::Accepted);
+        assert!(review
+            .response()
+            .patch
+            .contains("@@ -10,3 +11,4 @@ fn main\n ten\n eleven\n+eleven and a half\n twelve\n"));
+    }
+
+    #[test]
+    fn plain_unified_diffs_get_git_headers() {
+        let mut review =
+            Review::new("--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1 @@\n+hi\n", &[]).unwrap();
+        review.decide_rest(Status::Accepted);
+        assert_eq!(
+            review.response().patch,
+            "diff --git a/new.txt b/new.txt\nnew file mode 100644\n--- /dev/null\n+++ b/new.txt\n@@ -0,0 +1,1 @@\n+hi\n"
+        );
+    }
+
+    #[test]
+    fn keeps_trailing_no_newline_markers() {
+        let diff = "\
+diff --git a/f b/f
+--- a/f
++++ b/f
+@@ -1,2 +1,2 @@
+ a
+-b
+\\ No newline at end of file
++B
+\\ No newline at end of file
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        review.decide_rest(Status::Accepted);
+        assert_eq!(review.response().patch, diff);
+    }
+
+    const FILE_CHANGES: &str = "\
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+index 1111111..2222222
+--- a/run.sh
++++ b/run.sh
+@@ -1,2 +1,2 @@
+ #!/bin/sh
+-echo hi
++echo hello
+diff --git a/logo.png b/logo.png
+index 3333333..4444444 100644
+GIT binary patch
+literal 3
+KcmZQzWMT jsonb
+
+literal 2
+JcmZQzWMT jsonb
+
+diff --git a/old.txt b/old.txt
+deleted file mode 100644
+index 5555555..0000000
+--- a/old.txt
++++ /dev/null
+@@ -1,2 +0,0 @@
+-bye
+-now
+";
+
+    #[test]
+    fn file_level_changes_are_reviewed_as_items() {
+        let review = Review::new(FILE_CHANGES, &[]).unwrap();
+        let files = review.files();
+        assert!(files[0].mode_changed());
+        assert!(!files[0].is_whole_file());
+        assert!(files[1].change.binary);
+        assert_eq!(files[2].change.kind(), Kind::Deleted);
+        // mode change + its hunk, binary file, deleted file
+        assert_eq!(review.progress(), (1, 4));
+    }
+
+    #[test]
+    fn rejecting_file_level_changes_keeps_accepted_hunks() {
+        let mut review = Review::new(FILE_CHANGES, &[]).unwrap();
+        review.decide(Status::Rejected);
+        review.decide(Status::Accepted);
+        review.decide(Status::Accepted);
+        review.decide(Status::Rejected);
+        let response = review.response();
+        assert_eq!(response.status(), Status::Partial);
+        assert_eq!(response.files.len(), 3);
+        assert_eq!(response.hunks.len(), 1);
+        assert_eq!(
+            response.patch,
+            "\
+diff --git a/run.sh b/run.sh
+--- a/run.sh
++++ b/run.sh
+@@ -1,2 +1,2 @@
+ #!/bin/sh
+-echo hi
++echo hello
+diff --git a/logo.png b/logo.png
+index 3333333..4444444 100644
+GIT binary patch
+literal 3
+KcmZQzWMT jsonb
+
+literal 2
+JcmZQzWMT jsonb
+
+"
+        );
+    }
+
+    #[test]
+    fn mode_changes_can_be_accepted_without_their_hunks() {
+        let mut review = Review::new(FILE_CHANGES, &[]).unwrap();
+        review.decide(Status::Accepted);
+        review.decide_rest(Status::Rejected);
+        assert_eq!(
+            review.response().patch,
+            "diff --git a/run.sh b/run.sh\nold mode 100644\nnew mode 100755\n"
+        );
+    }
+
+    #[test]
+    fn pure_renames_are_a_single_item() {
+        let diff = "\
+diff --git a/a.rs b/b.rs
+similarity index 100%
+rename from a.rs
+rename to b.rs
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        let file = &review.files()[0];
+        assert!(file.is_pure_move());
+        assert_eq!(file.similarity(), Some(100));
+        assert_eq!(review.progress(), (1, 1));
+        review.decide(Status::Accepted);
+        assert_eq!(review.response().patch, diff);
+    }
+
+    #[test]
+    fn rejecting_a_copy_rejects_its_hunks() {
+        let diff = "\
+diff --git a/a.rs b/b.rs
+similarity index 90%
+copy from a.rs
+copy to b.rs
+--- a/a.rs
++++ b/b.rs
+@@ -1,2 +1,2 @@
+ fn a() {}
+-fn b() {}
++fn c() {}
+";
+        let mut review = Review::new(diff, &[]).unwrap();
+        review.move_cursor(1);
+        review.decide(Status::Accepted);
+        review.decide(Status::Rejected);
+        let response = review.response();
+        assert_eq!(response.status(), Status::Rejected);
+        assert_eq!(response.hunks[0].status(), Status::Rejected);
+        assert_eq!(response.patch, "");
+    }
+}
@@ -1,47 +1,116 @@
-use std::{io::Error as IoError, net::SocketAddr};
+use std::{
+    future::Future, io::Error as IoError, net::SocketAddr, path::PathBuf, sync::Arc, time::Instant,
+};
 
-use futures_util::{SinkExt, StreamExt};
+use anyhow::Context;
+use chrono::Utc;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
 use log::{info, warn};
 use prost::Message as _;
 use tokio::{
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
     select,
     sync::mpsc::{channel, Receiver, Sender},
+    task::JoinSet,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{frame::coding::CloseCode, CloseFrame, Message},
 };
-use tokio_tungstenite::tungstenite::protocol::Message;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    models::Patch,
-    tui::{self, PatchRequest},
+    args::ServerArgs,
+    audit::AuditLog,
+    auth::{Authenticate, Tokens},
+    config::{Rules, ServerConfig},
+    models::{client_message, server_message, ClientMessage, PatchResponse, ServerMessage},
+    store::{self, Received, Relay, Store, Submission},
+    tls,
+    tui::{self, ContextChannel, PatchRequest},
 };
 
-const SERVER_URL: &str = "127.0.0.1:8443";
-
-pub struct Server;
+pub struct Server {
+    listen: String,
+    /// certificate and key, if connections should be wrapped in TLS
+    tls: Option<(PathBuf, PathBuf)>,
+    /// file of tokens clients must authenticate with, if any
+    tokens: Option<PathBuf>,
+    /// named clients and the rules for what they may submit
+    config: Option<PathBuf>,
+    /// where the queue and decisions are kept across restarts
+    state: PathBuf,
+    /// where every decision is recorded
+    audit_log: PathBuf,
+}
 
-impl Server {
-    pub fn new() -> Self {
-        Server
+impl From<ServerArgs> for Server {
+    fn from(args: ServerArgs) -> Self {
+        Server {
+            listen: args.listen,
+            tls: args.tls_cert.zip(args.tls_key),
+            tokens: args.tokens,
+            config: args.config,
+            state: args.state,
+            audit_log: args.audit_log,
+        }
     }
+}
 
+impl Server {
     pub async fn run(&self) -> anyhow::Result<()> {
         let token = CancellationToken::new();
         // arbitrarily decided: should think about this more
         // can maybe even just use oneshot channel
         let (tx, rx) = channel::<PatchRequest>(10);
 
-        let tui = tokio::spawn(run_tui(token.clone(), rx));
-        let patch = tokio::spawn(run_patch_server(token.clone(), tx));
-        // TODO: this should be a join since we want both to get a chance to shutdown gracefully
-        tokio::select! {
-            // ctrl_c is handled in TUI event loop bc of raw mode
-            //_ = token.cancelled() => {
-            //    info!("Token cancelled");
-            //},
-            _ = tui => {},
-            _ = patch => {},
+        // bind before taking over the terminal, so a bad address (or certificate) is reported
+        let listener = TcpListener::bind(&self.listen)
+            .await
+            .with_context(|| format!("couldn't listen on {}", self.listen))?;
+        let acceptor = match &self.tls {
+            Some((cert, key)) => Some(tls::acceptor(cert, key)?),
+            None => None,
+        };
+        let config = match &self.config {
+            Some(path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+        let tokens = match &self.tokens {
+            Some(path) => Some(Arc::new(Tokens::load(path)?)),
+            None if !config.clients.is_empty() => Some(Arc::new(Tokens::named(&config.clients))),
+            None => None,
+        };
+        info!(
+            "Listening on: {} ({})",
+            self.listen,
+            if acceptor.is_some() { "wss" } else { "ws" }
+        );
+        if tokens.is_none() {
+            warn!("No tokens or clients configured, accepting patches from anyone who can connect");
         }
+        let store = Arc::new(Store::open(&self.state, AuditLog::open(&self.audit_log)?)?);
+
+        let tui = tokio::spawn(run_tui(token.clone(), rx));
+        let mut restored = JoinSet::new();
+        restore(&store, &tx, &mut restored).await;
+        let patch = tokio::spawn(run_patch_server(
+            token.clone(),
+            listener,
+            acceptor,
+            tokens,
+            Arc::new(config.rules),
+            store,
+            tx,
+        ));
+        // ctrl_c is handled in TUI event loop bc of raw mode
+        let _ = tui.await;
+        token.cancel();
+        // the last decisions made in the TUI are still on their way to the store
+        let _ = patch.await;
+        restored.join_all().await;
         Ok(())
     }
 }
@@ -54,43 +123,136 @@ async fn run_tui(token: CancellationToken, rx: Receiver<PatchRequest>) -> anyhow
     Ok(())
 }
 
+/// queues the patches that were still waiting for review when the server last stopped
+async fn restore(store: &Arc<Store>, tx: &Sender<PatchRequest>, tasks: &mut JoinSet<()>) {
+    for received in store.pending() {
+        let (response_tx, mut response_rx) = channel(1);
+        let mut request = match PatchRequest::try_from((received.patch, response_tx)) {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("Couldn't restore patch {}: {:#}", received.id, e);
+                continue;
+            }
+        };
+        request.client = received.client;
+        // answered once the client reconnects
+        let (context_tx, context_rx) = channel(8);
+        let (expanded_tx, expanded_rx) = channel(8);
+        request.context = Some(ContextChannel {
+            requests: context_tx,
+            responses: expanded_rx,
+        });
+        store.park(
+            &received.id,
+            Relay {
+                requests: context_rx,
+                responses: expanded_tx,
+            },
+        );
+        let age = (Utc::now() - received.at).to_std().unwrap_or_default();
+        request.received = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+        if tx.send(request).await.is_err() {
+            return;
+        }
+        // nobody's connected to hear the decision, so it waits in the store for the client
+        let (store, id) = (store.clone(), received.id);
+        tasks.spawn(async move {
+            if let Some(response) = response_rx.recv().await {
+                if let Err(e) = store.decide(&id, &response) {
+                    warn!("Couldn't record the decision for {}: {:#}", id, e);
+                }
+            }
+        });
+    }
+}
+
 async fn run_patch_server(
     token: CancellationToken,
+    listener: TcpListener,
+    acceptor: Option<TlsAcceptor>,
+    tokens: Option<Arc<Tokens>>,
+    rules: Arc<Rules>,
+    store: Arc<Store>,
     tx: Sender<PatchRequest>,
 ) -> Result<(), IoError> {
-    // Create the event loop and TCP listener we'll accept connections on.
-    let try_socket = TcpListener::bind(&SERVER_URL).await;
-    let listener = try_socket.expect("Failed to bind");
-    info!("Listening on: {}", SERVER_URL);
-
+    let mut connections = JoinSet::new();
     loop {
         tokio::select! {
             cxn = listener.accept() => {
                 if let Ok((stream, addr)) = cxn {
                     info!("Accepted listener as {}", addr);
-                    tokio::spawn(handle_connection(token.clone(), stream, addr, tx.clone()));
+                    let (token, tokens, rules, store, tx) =
+                        (token.clone(), tokens.clone(), rules.clone(), store.clone(), tx.clone());
+                    match acceptor.clone() {
+                        Some(acceptor) => connections.spawn(async move {
+                            let accepted = select! {
+                                accepted = acceptor.accept(stream) => accepted,
+                                _ = token.cancelled() => return,
+                            };
+                            match accepted {
+                                Ok(stream) => handle_connection(token, stream, addr, tokens, rules, store, tx).await,
+                                Err(e) => warn!("TLS handshake with {} failed: {}", addr, e),
+                            }
+                        }),
+                        None => connections.spawn(handle_connection(token, stream, addr, tokens, rules, store, tx)),
+                    };
                 }
             }
+            Some(_) = connections.join_next() => {}
             _ = token.cancelled() => {
                 info!("Shutting down from signal");
+                // let each connection record a decision it's already been given
+                connections.join_all().await;
                 return Ok(())
             }
         }
     }
 }
 
-async fn handle_connection(
+async fn handle_connection<S>(
     token: CancellationToken,
-    raw_stream: TcpStream,
+    raw_stream: S,
     addr: SocketAddr,
+    tokens: Option<Arc<Tokens>>,
+    rules: Arc<Rules>,
+    store: Arc<Store>,
     tx: Sender<PatchRequest>,
-) {
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     info!("Incoming TCP connection from: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
-        .await
-        .expect("Error during the websocket handshake occurred");
-    info!("WebSocket connection established: {}", addr);
+    let mut client = None;
+    let handshake = async {
+        match &tokens {
+            Some(tokens) => {
+                let authenticate = Authenticate {
+                    tokens,
+                    addr,
+                    client: &mut client,
+                };
+                tokio_tungstenite::accept_hdr_async(raw_stream, authenticate).await
+            }
+            None => tokio_tungstenite::accept_async(raw_stream).await,
+        }
+    };
+    // a client that never finishes its handshake mustn't hold up shutdown
+    let handshake = select! {
+        handshake = handshake => handshake,
+        _ = token.cancelled() => return,
+    };
+    let ws_stream = match handshake {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            warn!("Websocket handshake with {} failed: {}", addr, e);
+            return;
+        }
+    };
+    info!(
+        "WebSocket connection established: {} ({})",
+        addr,
+        client.as_deref().unwrap_or("anonymous")
+    );
 
     let (mut outgoing, mut incoming) = ws_stream.split();
 
@@ -99,25 +261,118 @@ async fn handle_connection(
             msg = incoming.next() => {
                 match msg {
                     Some(Ok(Message::Binary(b))) => {
-                        let patch = Patch::decode(b).unwrap();
+                        let patch = match ClientMessage::decode(b).map(|m| m.message) {
+                            Ok(Some(client_message::Message::Patch(patch))) => patch,
+                            _ => {
+                                warn!("Expected a patch from {}, ignoring the message", addr);
+                                continue
+                            }
+                        };
                         info!("Received a message from {}: {:?}", addr, patch.metadata);
+                        if !rules.allows(client.as_deref(), patch.repo.as_deref()) {
+                            warn!(
+                                "Dropped patch for {} from {} ({}): denied by config",
+                                patch.repo.as_deref().unwrap_or("unknown repo"),
+                                addr,
+                                client.as_deref().unwrap_or("anonymous")
+                            );
+                            let close = CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: "patch denied by the server's rules".into(),
+                            };
+                            let _ = outgoing.send(Message::Close(Some(close))).await;
+                            return
+                        }
+                        // a patch that can't be reviewed is never queued
                         let (response_tx, mut response_rx) = channel(1);
-                        let request = PatchRequest::try_from((patch, response_tx)).expect("patches should all be valid");
+                        let mut request = match PatchRequest::try_from((patch.clone(), response_tx)) {
+                            Ok(request) => request,
+                            Err(e) => {
+                                warn!("Dropped patch from {}: {:#}", addr, e);
+                                let close = CloseFrame {
+                                    code: CloseCode::Invalid,
+                                    reason: "the server couldn't parse the patch".into(),
+                                };
+                                let _ = outgoing.send(Message::Close(Some(close))).await;
+                                return
+                            }
+                        };
+                        let id = store::patch_id(client.as_deref(), &patch);
+                        let received = Received {
+                            id: id.clone(),
+                            addr: Some(addr.to_string()),
+                            client: client.clone(),
+                            at: Utc::now(),
+                            patch,
+                        };
+                        match store.submit(received) {
+                            Ok(Submission::New) => {}
+                            Ok(Submission::Decided(response)) => {
+                                info!("{} sent a patch that's already been decided", addr);
+                                respond(&mut outgoing, &store, &id, response, addr).await;
+                                continue
+                            }
+                            Ok(Submission::Pending(decided, relay)) => {
+                                info!("{} sent a patch that's already queued, waiting for its decision", addr);
+                                // whoever has the relay is still answering for it
+                                let mut relay = relay.unwrap_or_else(Relay::none);
+                                let decided = async { decided.await.ok() };
+                                match relay_until(&token, &mut outgoing, &mut incoming, &mut relay, decided).await {
+                                    Relayed::Decided(Some(response)) => respond(&mut outgoing, &store, &id, response, addr).await,
+                                    Relayed::Decided(None) | Relayed::Cancelled => {}
+                                    Relayed::Disconnected => {
+                                        info!("{} disconnected while waiting for its patch's decision", addr);
+                                        store.park(&id, relay);
+                                        return
+                                    }
+                                }
+                                continue
+                            }
+                            // the patch can still be reviewed, it just won't survive a restart
+                            Err(e) => warn!("Couldn't record the patch from {}: {:#}", addr, e),
+                        }
+                        let (context_tx, context_rx) = channel(8);
+                        let (expanded_tx, expanded_rx) = channel(8);
+                        request.client = client.clone();
+                        request.context = Some(ContextChannel {
+                            requests: context_tx,
+                            responses: expanded_rx,
+                        });
                         tx.send(request).await.unwrap();
                         info!("Sent state update from addr {}", addr);
 
-                        select! {
-                            response = response_rx.recv() => {
-                                info!("Received state update");
-                                match response {
-                                    None => info!("Empty update, channel closed"),
-                                    Some(response) => {
-                                        outgoing.send(response.encode_to_vec().into()).await.expect("failed to send");
-                                        info!("Sent response: {:?}", response);
-                                    }
+                        let mut relay = Relay {
+                            requests: context_rx,
+                            responses: expanded_tx,
+                        };
+                        let mut connected = true;
+                        let response = match relay_until(&token, &mut outgoing, &mut incoming, &mut relay, response_rx.recv()).await {
+                            Relayed::Decided(response) => response,
+                            Relayed::Cancelled => None,
+                            Relayed::Disconnected => {
+                                info!("{} disconnected while its patch was being reviewed", addr);
+                                connected = false;
+                                store.park(&id, relay);
+                                // the decision waits in the store for the client to come back
+                                select! {
+                                    biased;
+                                    response = response_rx.recv() => response,
+                                    _ = token.cancelled() => None,
                                 }
                             }
+                        };
+                        info!("Received state update");
+                        let Some(response) = response else {
+                            info!("Empty update, channel closed");
+                            continue
+                        };
+                        if let Err(e) = store.decide(&id, &response) {
+                            warn!("Couldn't record the decision for {}: {:#}", addr, e);
                         }
+                        if !connected {
+                            return
+                        }
+                        respond(&mut outgoing, &store, &id, response, addr).await;
                     }
                     None => {
                         info!("{} disconnected", &addr);
@@ -136,3 +391,235 @@ async fn handle_connection(
         }
     }
 }
+
+/// what ended a wait for the reviewer's decision
+enum Relayed {
+    Decided(Option<PatchResponse>),
+    Disconnected,
+    Cancelled,
+}
+
+/// passes the reviewer's requests for more context to the client, and its answers
+/// back, until `decided` resolves
+async fn relay_until<O, I>(
+    token: &CancellationToken,
+    outgoing: &mut O,
+    incoming: &mut I,
+    relay: &mut Relay,
+    decided: impl Future<Output = Option<PatchResponse>>,
+) -> Relayed
+where
+    O: Sink<Message, Error = tungstenite::Error> + Unpin,
+    I: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    tokio::pin!(decided);
+    loop {
+        // a decision sent just before the TUI quit must win over the cancellation
+        select! {
+            biased;
+            response = &mut decided => return Relayed::Decided(response),
+            Some(request) = relay.requests.recv() => {
+                let message = ServerMessage {
+                    message: Some(server_message::Message::Context(request)),
+                };
+                if let Err(e) = outgoing.send(message.encode_to_vec().into()).await {
+                    warn!("Couldn't ask for more context: {}", e);
+                }
+            }
+            msg = incoming.next() => match msg {
+                Some(Ok(Message::Binary(b))) => match ClientMessage::decode(b).map(|m| m.message) {
+                    Ok(Some(client_message::Message::Context(context))) => {
+                        let _ = relay.responses.send(context).await;
+                    }
+                    _ => warn!("Expected more context, ignoring the message"),
+                },
+                Some(Ok(_)) => {}
+                None | Some(Err(_)) => return Relayed::Disconnected,
+            },
+            _ = token.cancelled() => return Relayed::Cancelled,
+        }
+    }
+}
+
+/// sends the reviewer's decision, which the store can forget once the client has it
+async fn respond<O>(
+    outgoing: &mut O,
+    store: &Store,
+    id: &str,
+    response: PatchResponse,
+    addr: SocketAddr,
+) where
+    O: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let message = ServerMessage {
+        message: Some(server_message::Message::Response(response.clone())),
+    };
+    match outgoing.send(message.encode_to_vec().into()).await {
+        Ok(()) => {
+            info!("Sent response: {:?}", response);
+            if let Err(e) = store.delivered(id) {
+                warn!("Couldn't record that {} has its decision: {:#}", addr, e);
+            }
+        }
+        // kept for when the client sends the patch again
+        Err(e) => warn!("Couldn't send {} its decision: {}", addr, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{duplex, DuplexStream},
+        net::TcpStream,
+    };
+    use tokio_tungstenite::{client_async, WebSocketStream};
+
+    use super::*;
+    use crate::models::{ContextRequest, ContextResponse, Patch};
+
+    /// connects a client to `handle_connection` over an in-memory stream
+    async fn connect(
+        token: &CancellationToken,
+        store: &Arc<Store>,
+        tx: &Sender<PatchRequest>,
+    ) -> WebSocketStream<DuplexStream> {
+        let (client, server) = duplex(1 << 16);
+        tokio::spawn(handle_connection(
+            token.clone(),
+            server,
+            "127.0.0.1:1".parse().unwrap(),
+            None,
+            Arc::new(Rules::default()),
+            store.clone(),
+            tx.clone(),
+        ));
+        client_async("ws://localhost/", client).await.unwrap().0
+    }
+
+    async fn send<S>(ws: &mut WebSocketStream<S>, message: client_message::Message)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let message = ClientMessage {
+            message: Some(message),
+        };
+        ws.send(message.encode_to_vec().into()).await.unwrap();
+    }
+
+    async fn receive(ws: &mut WebSocketStream<DuplexStream>) -> server_message::Message {
+        let next = tokio::time::timeout(Duration::from_secs(5), ws.next());
+        match next.await.expect("nothing was sent") {
+            Some(Ok(Message::Binary(b))) => ServerMessage::decode(b).unwrap().message.unwrap(),
+            other => panic!("expected a message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn resent_patches_pick_up_their_review_after_a_disconnect() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::open(&dir.path().join("audit.jsonl")).unwrap();
+        let store = Arc::new(Store::open(&dir.path().join("state.jsonl"), audit).unwrap());
+        let token = CancellationToken::new();
+        let (tx, mut rx) = channel(10);
+        let patch = Patch {
+            patch: "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n".into(),
+            id: "run".into(),
+            ..Default::default()
+        };
+        let id = store::patch_id(None, &patch);
+
+        let mut first = connect(&token, &store, &tx).await;
+        send(&mut first, client_message::Message::Patch(patch.clone())).await;
+        let mut request = rx.recv().await.unwrap();
+        drop(first);
+        while !store.parked(&id) {
+            tokio::task::yield_now().await;
+        }
+
+        // the reconnected client answers for the queued request
+        let mut second = connect(&token, &store, &tx).await;
+        send(&mut second, client_message::Message::Patch(patch)).await;
+        let context = request.context.as_mut().unwrap();
+        let asked = ContextRequest {
+            path: "f".into(),
+            start: 1,
+            end: 2,
+            ..Default::default()
+        };
+        context.requests.send(asked.clone()).await.unwrap();
+        assert_eq!(
+            receive(&mut second).await,
+            server_message::Message::Context(asked)
+        );
+        let answer = ContextResponse {
+            lines: vec!["b".into()],
+            ..Default::default()
+        };
+        send(
+            &mut second,
+            client_message::Message::Context(answer.clone()),
+        )
+        .await;
+        assert_eq!(context.responses.recv().await.unwrap(), answer);
+        assert!(rx.try_recv().is_err(), "the patch was queued twice");
+
+        let response = PatchResponse {
+            patch: "accepted".into(),
+            ..Default::default()
+        };
+        request.response_chan.send(response.clone()).await.unwrap();
+        assert_eq!(
+            receive(&mut second).await,
+            server_message::Message::Response(response)
+        );
+        let again = tokio::time::timeout(Duration::from_millis(100), second.next()).await;
+        assert!(again.is_err(), "the decision was sent twice");
+        assert!(store.pending().is_empty());
+        token.cancel();
+    }
+
+    #[tokio::test]
+    async fn decisions_made_as_the_server_stops_are_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::open(&dir.path().join("audit.jsonl")).unwrap();
+        let store = Arc::new(Store::open(&dir.path().join("state.jsonl"), audit).unwrap());
+        let token = CancellationToken::new();
+        let (tx, mut rx) = channel(10);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(run_patch_server(
+            token.clone(),
+            listener,
+            None,
+            None,
+            Arc::new(Rules::default()),
+            store.clone(),
+            tx,
+        ));
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut ws = client_async(format!("ws://{addr}/"), stream)
+            .await
+            .unwrap()
+            .0;
+        let patch = Patch {
+            patch: "--- a/f\n+++ b/f\n@@ -1 +1 @@\n-a\n+b\n".into(),
+            ..Default::default()
+        };
+        send(&mut ws, client_message::Message::Patch(patch)).await;
+        let request = rx.recv().await.unwrap();
+        assert_eq!(store.pending().len(), 1);
+
+        // the way the TUI quits after accepting everything that's left
+        request
+            .response_chan
+            .send(PatchResponse::default())
+            .await
+            .unwrap();
+        token.cancel();
+        server.await.unwrap().unwrap();
+        assert!(store.pending().is_empty());
+    }
+}
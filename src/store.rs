@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::Path,
+    sync::{Mutex, MutexGuard},
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{
+    mpsc::{channel, Receiver, Sender},
+    oneshot,
+};
+
+use crate::{
+    audit::{AuditLog, Entry},
+    models::{ContextRequest, ContextResponse, Patch, PatchResponse},
+};
+
+/// a patch the server has been sent
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Received {
+    /// recognises the patch when it's sent again, see [`patch_id`]
+    pub id: String,
+    /// address it was sent from
+    #[serde(default)]
+    pub addr: Option<String>,
+    /// name of the authenticated client that submitted it
+    pub client: Option<String>,
+    pub at: DateTime<Utc>,
+    pub patch: Patch,
+}
+
+/// a line of the state file
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Received(Received),
+    Decided {
+        id: String,
+        response: PatchResponse,
+    },
+    /// the decision made it back to the client, so there's nothing left to keep
+    Delivered {
+        id: String,
+    },
+}
+
+/// the server's end of a queued patch's [`ContextChannel`](crate::tui::ContextChannel),
+/// passed from one of its client's connections to the next
+#[derive(Debug)]
+pub struct Relay {
+    pub requests: Receiver<ContextRequest>,
+    pub responses: Sender<ContextResponse>,
+}
+
+impl Relay {
+    /// a relay nothing is ever asked through
+    pub fn none() -> Self {
+        let (_, requests) = channel(1);
+        let (responses, _) = channel(1);
+        Relay {
+            requests,
+            responses,
+        }
+    }
+}
+
+/// what became of a submitted patch
+#[derive(Debug)]
+pub enum Submission {
+    /// it hasn't been seen before, and should be queued for review
+    New,
+    /// it's already queued (e.g. sent before the client reconnected), so wait for
+    /// its decision, relaying requests for context if nobody else is
+    Pending(oneshot::Receiver<PatchResponse>, Option<Relay>),
+    /// the reviewer already decided, but the client never heard
+    Decided(PatchResponse),
+}
+
+/// the server's append-only log of the patches it's been sent and what the
+/// reviewer decided, so that a restarted server can pick up where it left off
+pub struct Store {
+    inner: Mutex<Inner>,
+    /// where every decision is recorded for good
+    audit: AuditLog,
+    /// held while the store is open, so no other server shares its state file
+    _lock: File,
+}
+
+struct Inner {
+    file: File,
+    /// undecided patches, in the order they arrived
+    pending: Vec<Received>,
+    /// decisions that haven't made it back to their clients
+    decided: HashMap<String, PatchResponse>,
+    /// clients waiting on a pending patch's decision
+    waiting: HashMap<String, Vec<oneshot::Sender<PatchResponse>>>,
+    /// context relays of pending patches whose client isn't connected
+    relays: HashMap<String, Relay>,
+}
+
+impl Store {
+    /// replays the log at `path`, if there is one, then rewrites it with only what's
+    /// still needed
+    pub fn open(path: &Path, audit: AuditLog) -> anyhow::Result<Self> {
+        // the log itself is replaced when it's compacted, so the lock lives beside it
+        let lock_path = path.with_extension("lock");
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("couldn't write {}", lock_path.display()))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => bail!(
+                "another server is using {}, give each one its own --state and --audit-log",
+                path.display()
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("couldn't lock {}", lock_path.display()))
+            }
+        }
+
+        let mut pending: Vec<Received> = vec![];
+        let mut decided = HashMap::new();
+        match File::open(path) {
+            Ok(file) => {
+                for (idx, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line.with_context(|| format!("couldn't read {}", path.display()))?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    // a crash partway through writing a line loses only that line
+                    match serde_json::from_str(&line) {
+                        Ok(Record::Received(received)) => pending.push(received),
+                        Ok(Record::Decided { id, response }) => {
+                            pending.retain(|r| r.id != id);
+                            decided.insert(id, response);
+                        }
+                        Ok(Record::Delivered { id }) => {
+                            decided.remove(&id);
+                        }
+                        Err(e) => warn!("Skipping line {} of {}: {}", idx + 1, path.display(), e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("couldn't read {}", path.display())),
+        }
+
+        let compacted = path.with_extension("tmp");
+        let mut inner = Inner {
+            file: File::create(&compacted)
+                .with_context(|| format!("couldn't write {}", compacted.display()))?,
+            pending: vec![],
+            decided: HashMap::new(),
+            waiting: HashMap::new(),
+            relays: HashMap::new(),
+        };
+        for received in pending {
+            inner.write(&Record::Received(received.clone()))?;
+            inner.pending.push(received);
+        }
+        for (id, response) in decided {
+            inner.write(&Record::Decided {
+                id: id.clone(),
+                response: response.clone(),
+            })?;
+            inner.decided.insert(id, response);
+        }
+        inner.file.sync_all()?;
+        fs::rename(&compacted, path)
+            .with_context(|| format!("couldn't replace {}", path.display()))?;
+        inner.file = OpenOptions::new().append(true).open(path)?;
+
+        info!(
+            "Restored {} pending patch(es) and {} undelivered decision(s) from {}",
+            inner.pending.len(),
+            inner.decided.len(),
+            path.display()
+        );
+        Ok(Store {
+            inner: Mutex::new(inner),
+            audit,
+            _lock: lock,
+        })
+    }
+
+    /// the patches still waiting for review, oldest first
+    pub fn pending(&self) -> Vec<Received> {
+        self.lock().pending.clone()
+    }
+
+    /// records a submitted patch, unless it's one the store already knows about
+    pub fn submit(&self, received: Received) -> anyhow::Result<Submission> {
+        let mut inner = self.lock();
+        if let Some(response) = inner.decided.get(&received.id) {
+            return Ok(Submission::Decided(response.clone()));
+        }
+        if inner.pending.iter().any(|r| r.id == received.id) {
+            let (tx, rx) = oneshot::channel();
+            let relay = inner.relays.remove(&received.id);
+            inner.waiting.entry(received.id).or_default().push(tx);
+            return Ok(Submission::Pending(rx, relay));
+        }
+        // still reviewed (and audited) if it can't be written, just not restored
+        inner.pending.push(received.clone());
+        inner.write(&Record::Received(received))?;
+        Ok(Submission::New)
+    }
+
+    /// keeps a pending patch's relay for the next time its client connects
+    pub fn park(&self, id: &str, relay: Relay) {
+        let mut inner = self.lock();
+        if inner.pending.iter().any(|r| r.id == id) {
+            inner.relays.entry(id.to_string()).or_insert(relay);
+        }
+    }
+
+    /// whether a pending patch's relay is waiting for its client to reconnect
+    #[cfg(test)]
+    pub fn parked(&self, id: &str) -> bool {
+        self.lock().relays.contains_key(id)
+    }
+
+    /// records the reviewer's decision, passing it on to any clients waiting for it
+    pub fn decide(&self, id: &str, response: &PatchResponse) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+        inner.relays.remove(id);
+        if let Some(idx) = inner.pending.iter().position(|r| r.id == id) {
+            let received = inner.pending.remove(idx);
+            if let Err(e) = self.audit.record(&Entry::new(&received, response)) {
+                warn!("Couldn't write {}'s decision to the audit log: {:#}", id, e);
+            }
+        }
+        inner.decided.insert(id.to_string(), response.clone());
+        for waiting in inner.waiting.remove(id).unwrap_or_default() {
+            let _ = waiting.send(response.clone());
+        }
+        inner.write(&Record::Decided {
+            id: id.to_string(),
+            response: response.clone(),
+        })
+    }
+
+    /// forgets a decision once its client has it
+    pub fn delivered(&self, id: &str) -> anyhow::Result<()> {
+        let mut inner = self.lock();
+        if inner.decided.remove(id).is_some() {
+            inner.write(&Record::Delivered { id: id.to_string() })?;
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().expect("store lock poisoned")
+    }
+}
+
+impl Inner {
+    fn write(&mut self, record: &Record) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// a hash of who sent the patch and the ID they gave it (or, for clients that
+/// don't, its contents), the same whenever it's sent again
+pub fn patch_id(client: Option<&str>, patch: &Patch) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(client.unwrap_or_default());
+    hasher.update([0]);
+    if patch.id.is_empty() {
+        hasher.update(patch.encode_to_vec());
+    } else {
+        hasher.update(&patch.id);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(patch: &str) -> Received {
+        let patch = Patch {
+            patch: patch.to_string(),
+            ..Default::default()
+        };
+        Received {
+            id: patch_id(None, &patch),
+            addr: None,
+            client: None,
+            at: Utc::now(),
+            patch,
+        }
+    }
+
+    #[test]
+    fn restores_pending_patches_and_undelivered_decisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("patchpal-state.jsonl");
+        let audit_path = dir.path().join("patchpal-audit.jsonl");
+        let audit = || AuditLog::open(&audit_path).unwrap();
+        let (first, second, third) = (received("a"), received("b"), received("c"));
+        let response = PatchResponse {
+            patch: "b".into(),
+            ..Default::default()
+        };
+
+        let store = Store::open(&path, audit()).unwrap();
+        let other_audit = AuditLog::open(&dir.path().join("other-audit.jsonl")).unwrap();
+        assert!(Store::open(&path, other_audit).is_err());
+        assert!(AuditLog::open(&audit_path).is_err());
+        for received in [&first, &second, &third] {
+            assert!(matches!(
+                store.submit(received.clone()).unwrap(),
+                Submission::New
+            ));
+        }
+        store.decide(&second.id, &response).unwrap();
+        store.decide(&third.id, &response).unwrap();
+        store.delivered(&third.id).unwrap();
+        drop(store);
+
+        let store = Store::open(&path, audit()).unwrap();
+        let pending: Vec<_> = store.pending().into_iter().map(|r| r.id).collect();
+        assert_eq!(pending, vec![first.id.clone()]);
+        assert!(matches!(
+            store.submit(first).unwrap(),
+            Submission::Pending(..)
+        ));
+        match store.submit(second.clone()).unwrap() {
+            Submission::Decided(decided) => assert_eq!(decided, response),
+            other => panic!("expected a decision, got {other:?}"),
+        }
+        // a delivered decision doesn't stop the same patch being reviewed again
+        assert!(matches!(
+            store.submit(third.clone()).unwrap(),
+            Submission::New
+        ));
+
+        let audited: Vec<Entry> = fs::read_to_string(&audit_path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let audited: Vec<_> = audited.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(audited, vec![second.id.as_str(), third.id.as_str()]);
+    }
+}
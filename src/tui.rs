@@ -1,14 +1,24 @@
-use std::time::Duration;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    ops::{Range, RangeInclusive},
+    path::Path,
+    process::Command,
+    time::{Duration, Instant},
+};
 
+use anyhow::{bail, Context};
 use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
 use futures_util::StreamExt;
-use log::info;
+use log::{info, warn};
 use ratatui::{
     buffer::Buffer,
-    layout::{Rect, Size},
-    style::Stylize,
-    text::{Line, Text},
-    widgets::{Block, Padding, Paragraph, StatefulWidget, Widget, Wrap},
+    layout::{Constraint, Layout, Position, Rect, Size},
+    style::{Color, Style, Stylize},
+    text::{Line, Span, Text},
+    widgets::{
+        Block, Borders, List, ListItem, ListState, Padding, Paragraph, StatefulWidget, Widget, Wrap,
+    },
     DefaultTerminal,
     Frame,
 };
@@ -19,65 +29,367 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tui_scrollview::{ScrollView, ScrollViewState, ScrollbarVisibility};
-use unidiff::PatchSet;
+use unicode_width::UnicodeWidthChar;
+use unidiff::Hunk;
+
+use crate::{
+    highlight::{highlight_file, HunkHighlights},
+    models::{
+        file_change::Kind, patch_response::Status, ContextRequest, ContextResponse, Patch,
+        PatchResponse,
+    },
+    review::{self, new_side, FileDiff, ItemId, Review},
+};
+
+/// how many more lines of context each expansion shows on either side of a hunk
+const EXPAND_LINES: usize = 10;
+
+const ADDED_BG: Color = Color::Rgb(0x1d, 0x3b, 0x24);
+const REMOVED_BG: Color = Color::Rgb(0x4b, 0x1f, 0x24);
+const ADDED_WORDS_BG: Color = Color::Rgb(0x2e, 0x6b, 0x3c);
+const REMOVED_WORDS_BG: Color = Color::Rgb(0x8c, 0x2f, 0x39);
+
+/// asks the patch's client for more of its files, see [`PatchRequest::expand`]
+#[derive(Debug)]
+pub struct ContextChannel {
+    pub requests: Sender<ContextRequest>,
+    pub responses: Receiver<ContextResponse>,
+}
+
+/// lines the client sent to show around a hunk, beyond its own context
+#[derive(Debug, Default)]
+pub struct Expansion {
+    before: Vec<String>,
+    after: Vec<String>,
+}
+
+/// the changed lines of the hunk under the cursor, picked out to be accepted on their own
+struct LineSelection {
+    id: ItemId,
+    /// the line under the line cursor (indexed like [`Hunk::lines`])
+    cursor: usize,
+    /// where the range being selected started, if there is one
+    anchor: Option<usize>,
+    /// which lines are accepted
+    selected: Vec<bool>,
+}
 
-use crate::models::{patch_response::Status, Patch, PatchResponse};
+impl LineSelection {
+    /// starts with every changed line selected, or `None` if the hunk has none
+    fn new(id: ItemId, hunk: &Hunk) -> Option<Self> {
+        let cursor = hunk.lines().iter().position(is_change)?;
+        Some(LineSelection {
+            id,
+            cursor,
+            anchor: None,
+            selected: vec![true; hunk.lines().len()],
+        })
+    }
+
+    /// moves to the next (or previous) changed line, if there is one
+    fn move_cursor(&mut self, hunk: &Hunk, forward: bool) {
+        let lines = hunk.lines();
+        let next = if forward {
+            (self.cursor + 1..lines.len()).find(|&idx| is_change(&lines[idx]))
+        } else {
+            (0..self.cursor).rev().find(|&idx| is_change(&lines[idx]))
+        };
+        self.cursor = next.unwrap_or(self.cursor);
+    }
+
+    /// the lines between the anchor and the cursor, or just the cursor's
+    fn range(&self) -> RangeInclusive<usize> {
+        let anchor = self.anchor.unwrap_or(self.cursor);
+        anchor.min(self.cursor)..=anchor.max(self.cursor)
+    }
+
+    /// selects the range's changed lines, or unselects them if they all already are
+    fn toggle(&mut self, hunk: &Hunk) {
+        let changes: Vec<usize> = self
+            .range()
+            .filter(|&idx| is_change(&hunk.lines()[idx]))
+            .collect();
+        let select = !changes.iter().all(|&idx| self.selected[idx]);
+        for idx in changes {
+            self.selected[idx] = select;
+        }
+        self.anchor = None;
+    }
 
-#[derive(Debug, Clone)]
+    fn is_left_out(&self, idx: usize) -> bool {
+        !self.selected.get(idx).copied().unwrap_or(true)
+    }
+
+    /// how to mark the line's numbers: the cursor's stand out, and a range's are yellow
+    fn gutter_style(&self, idx: usize) -> Option<Style> {
+        if idx == self.cursor {
+            Some(Style::new().yellow().reversed())
+        } else if self.anchor.is_some() && self.range().contains(&idx) {
+            Some(Style::new().yellow())
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct PatchRequest {
-    pub patch_set: PatchSet,
+    pub review: Review,
     pub metadata: Option<String>,
+    pub repo: Option<String>,
+    /// name of the authenticated client that submitted the patch
+    pub client: Option<String>,
+    pub received: Instant,
+    /// files whose hunks the reviewer has folded away
+    pub folded: BTreeSet<usize>,
+    /// syntax colors for each file's hunks, empty for unknown languages
+    pub highlights: Vec<Vec<HunkHighlights>>,
+    /// only present if the client can send more context
+    pub context: Option<ContextChannel>,
+    /// extra context shown around hunks, by file and hunk
+    pub expanded: BTreeMap<(usize, usize), Expansion>,
+    /// what went wrong with the last thing the reviewer tried, shown above the diff
+    pub error: Option<String>,
+    /// whether the client will commit what's accepted
+    pub commit: bool,
+    pub commit_message: Option<String>,
     pub response_chan: Sender<PatchResponse>,
 }
 
+impl PatchRequest {
+    fn response(&self) -> PatchResponse {
+        PatchResponse {
+            commit_message: self.commit_message.clone(),
+            ..self.review.response()
+        }
+    }
+
+    /// the cursor's item is never hidden
+    fn unfold_cursor(&mut self) {
+        if let Some(cursor) = self.review.cursor() {
+            self.folded.remove(&cursor.file);
+        }
+    }
+
+    /// splits the hunk under the cursor into smaller ones
+    fn split(&mut self) {
+        let Some(ItemId {
+            file,
+            hunk: Some(hunk),
+        }) = self.review.cursor()
+        else {
+            return;
+        };
+        let count = self.review.split_hunk(file, hunk);
+        if count == 1 {
+            self.error =
+                Some("Only hunks with unchanged lines between changes can be split".into());
+            return;
+        }
+        self.highlights[file] = highlight_file(&self.review.files()[file]);
+        // the split hunk's expansion goes around its first and last parts
+        let expanded = std::mem::take(&mut self.expanded);
+        for ((f, h), expansion) in expanded {
+            if f != file || h < hunk {
+                self.expanded.insert((f, h), expansion);
+            } else if h > hunk {
+                self.expanded.insert((f, h + count - 1), expansion);
+            } else {
+                let Expansion { before, after } = expansion;
+                self.expanded.insert(
+                    (f, h),
+                    Expansion {
+                        before,
+                        after: vec![],
+                    },
+                );
+                self.expanded.insert(
+                    (f, h + count - 1),
+                    Expansion {
+                        before: vec![],
+                        after,
+                    },
+                );
+            }
+        }
+        self.error = None;
+    }
+
+    /// asks the client for more lines around the hunk under the cursor, stopping
+    /// short of its neighbours
+    fn expand(&self) {
+        let Some(channel) = &self.context else {
+            return;
+        };
+        let Some(ItemId {
+            file,
+            hunk: Some(hunk),
+        }) = self.review.cursor()
+        else {
+            return;
+        };
+        let expanded = |hunk| self.expanded.get(&(file, hunk));
+        // in the client's numbering, which edits to this hunk or earlier ones don't change
+        let new_side = |hunk| self.review.context_range(file, hunk);
+        let hunks = self.review.files()[file].patched.hunks();
+        let (first, len) = new_side(hunk);
+        let before = expanded(hunk).map_or(0, |e| e.before.len());
+        let after = expanded(hunk).map_or(0, |e| e.after.len());
+
+        let floor = match hunk.checked_sub(1) {
+            Some(prev) => {
+                let (prev_first, prev_len) = new_side(prev);
+                prev_first + prev_len + expanded(prev).map_or(0, |e| e.after.len())
+            }
+            None => 1,
+        };
+        let start = (first - before).saturating_sub(EXPAND_LINES).max(floor);
+        let mut end = first + len + after + EXPAND_LINES - 1;
+        if hunk + 1 < hunks.len() {
+            let next_before = expanded(hunk + 1).map_or(0, |e| e.before.len());
+            end = end.min(new_side(hunk + 1).0.saturating_sub(next_before + 1));
+        }
+
+        let request = ContextRequest {
+            file: file as u32,
+            hunk: hunk as u32,
+            path: self.review.files()[file].path().to_string(),
+            start: start as u32,
+            end: end as u32,
+        };
+        if let Err(e) = channel.requests.try_send(request) {
+            warn!("Couldn't ask for more context: {}", e);
+        }
+    }
+
+    /// shows whatever context the client has sent back
+    fn receive_context(&mut self) {
+        let Some(channel) = &mut self.context else {
+            return;
+        };
+        while let Ok(response) = channel.responses.try_recv() {
+            if let Some(error) = response.error {
+                self.error = Some(format!("Couldn't show more context: {error}"));
+                continue;
+            }
+            let (file, hunk) = (response.file as usize, response.hunk as usize);
+            let files = self.review.files();
+            if files
+                .get(file)
+                .is_none_or(|f| hunk >= f.patched.hunks().len())
+            {
+                continue;
+            }
+            let (first, len) = self.review.context_range(file, hunk);
+            let start = response.start as usize;
+            // the response covers the hunk's own lines too, which are already shown
+            let expansion = Expansion {
+                before: response.lines[..first.saturating_sub(start).min(response.lines.len())]
+                    .to_vec(),
+                after: response
+                    .lines
+                    .iter()
+                    .skip((first + len).saturating_sub(start))
+                    .cloned()
+                    .collect(),
+            };
+            self.expanded.insert((file, hunk), expansion);
+            self.error = None;
+        }
+    }
+}
+
 impl TryFrom<(Patch, Sender<PatchResponse>)> for PatchRequest {
     type Error = anyhow::Error;
 
     fn try_from((patch, response_chan): (Patch, Sender<PatchResponse>)) -> anyhow::Result<Self> {
         let metadata = patch.metadata;
-        let patch_set = patch.patch.parse::<PatchSet>()?;
+        let review = Review::new(&patch.patch, &patch.files)?;
+        let highlights = review.files().iter().map(highlight_file).collect();
 
         Ok(PatchRequest {
-            patch_set,
+            review,
             metadata,
+            repo: patch.repo,
+            client: None,
+            received: Instant::now(),
+            folded: BTreeSet::new(),
+            highlights,
+            context: None,
+            expanded: BTreeMap::new(),
+            error: None,
+            commit: patch.commit,
+            commit_message: None,
             response_chan,
         })
     }
 }
 
+/// patches waiting for review, in the order they arrived
 struct Requests {
-    peek: Option<PatchRequest>,
+    pending: Vec<PatchRequest>,
+    /// index into `pending` of the one being reviewed
+    selected: usize,
     receiver: Receiver<PatchRequest>,
 }
 
 impl Requests {
+    /// moves newly submitted patches into the queue, and any context their clients sent
+    fn receive(&mut self) {
+        while let Ok(req) = self.receiver.try_recv() {
+            self.pending.push(req);
+        }
+        self.pending
+            .iter_mut()
+            .for_each(PatchRequest::receive_context);
+    }
+
+    /// removes the patch being reviewed, moving on to the next one
     fn pop(&mut self) -> Option<PatchRequest> {
-        let prev = self.peek.clone();
-        // if value waiting, pop it and make it peekable
-        if let Ok(req) = self.receiver.try_recv() {
-            self.peek = Some(req);
-            return prev;
+        self.receive();
+        if self.pending.is_empty() {
+            return None;
         }
-        // if no value waiting, set peek to none
-        self.peek = None;
-        prev
+        let req = self.pending.remove(self.selected);
+        self.selected = self.selected.min(self.pending.len().saturating_sub(1));
+        Some(req)
     }
 
     fn peek(&mut self) -> Option<&PatchRequest> {
-        if self.peek.is_some() {
-            return self.peek.as_ref();
-        }
-        // if value waiting, make it peekable
-        if let Ok(req) = self.receiver.try_recv() {
-            self.peek = Some(req)
+        self.receive();
+        self.pending.get(self.selected)
+    }
+
+    fn peek_mut(&mut self) -> Option<&mut PatchRequest> {
+        self.receive();
+        self.pending.get_mut(self.selected)
+    }
+
+    /// switches to the patch `offset` places away in the queue, wrapping around
+    fn select(&mut self, offset: isize) {
+        self.receive();
+        let len = self.pending.len() as isize;
+        if len > 0 {
+            self.selected = (self.selected as isize + offset).rem_euclid(len) as usize;
         }
-        self.peek.as_ref()
     }
 }
 
 pub struct App {
     requests: Requests,
     scroll_state: ScrollViewState,
+    /// set when the hunk cursor moves so the next render scrolls it into view
+    follow_cursor: bool,
+    /// commit message being typed by the reviewer, if any
+    message_input: Option<String>,
+    /// whether to list the active patch's files beside the diff
+    show_files: bool,
+    /// whether to show hunks side-by-side, when there's room for it
+    side_by_side: bool,
+    /// set when the reviewer asks to edit the current hunk, which needs the terminal
+    edit_requested: bool,
+    /// lines being picked out of the hunk under the cursor, if any
+    line_selection: Option<LineSelection>,
     exit: bool,
     frame_rate: f64,
 }
@@ -86,10 +398,17 @@ impl App {
     pub fn new(submit_rx: Receiver<PatchRequest>) -> Self {
         App {
             requests: Requests {
-                peek: None,
+                pending: vec![],
+                selected: 0,
                 receiver: submit_rx,
             },
             scroll_state: ScrollViewState::new(),
+            follow_cursor: true,
+            message_input: None,
+            show_files: true,
+            side_by_side: false,
+            edit_requested: false,
+            line_selection: None,
             exit: false,
             frame_rate: 30.0, // if it's good enough for TV, probably fine for me
         }
@@ -104,6 +423,10 @@ impl App {
         while !self.exit {
             terminal.draw(|frame| self.draw(frame))?;
             self.handle_events(token).await?;
+            if self.edit_requested {
+                self.edit_requested = false;
+                self.edit_hunk(terminal).await?;
+            }
         }
         ratatui::restore();
         Ok(())
@@ -141,6 +464,14 @@ impl App {
     }
 
     async fn handle_key_event(&mut self, key_event: KeyEvent) {
+        if self.message_input.is_some() {
+            self.handle_message_input(key_event);
+            return;
+        }
+        if self.line_selection.is_some() {
+            self.handle_line_selection(key_event).await;
+            return;
+        }
         match key_event {
             // must support <C-q> as well, since we run in raw mode
             KeyEvent {
@@ -159,10 +490,7 @@ impl App {
                 ..
             } => {
                 info!("got `yes` reponse");
-                self.handle_patch_response(PatchResponse {
-                    status: Status::Accepted.into(),
-                })
-                .await;
+                self.handle_hunk_decision(Status::Accepted).await;
             }
             KeyEvent {
                 code: KeyCode::Char('n'),
@@ -170,10 +498,7 @@ impl App {
                 ..
             } => {
                 info!("got `no` reponse");
-                self.handle_patch_response(PatchResponse {
-                    status: Status::Rejected.into(),
-                })
-                .await;
+                self.handle_hunk_decision(Status::Rejected).await;
             }
             KeyEvent {
                 code: KeyCode::Char('a'),
@@ -181,14 +506,7 @@ impl App {
                 ..
             } => {
                 info!("accepting all remaining");
-                while let Some(req) = self.requests.pop() {
-                    let _ = req
-                        .response_chan
-                        .send(PatchResponse {
-                            status: Status::Accepted.into(),
-                        })
-                        .await;
-                }
+                self.decide_all_remaining(Status::Accepted).await;
                 self.exit = true;
             }
             KeyEvent {
@@ -197,16 +515,84 @@ impl App {
                 ..
             } => {
                 info!("rejecting all remaining");
-                while let Some(req) = self.requests.pop() {
-                    let _ = req
-                        .response_chan
-                        .send(PatchResponse {
-                            status: Status::Accepted.into(),
-                        })
-                        .await;
-                }
+                self.decide_all_remaining(Status::Rejected).await;
                 self.exit = true;
             }
+            KeyEvent {
+                code: KeyCode::Char('m'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => {
+                if let Some(req) = self.requests.peek().filter(|r| r.commit) {
+                    self.message_input = Some(req.commit_message.clone().unwrap_or_default());
+                }
+            }
+            KeyEvent {
+                code: KeyCode::Char('J'),
+                ..
+            } => self.move_cursor(|review| review.move_cursor(1)),
+            KeyEvent {
+                code: KeyCode::Char('K'),
+                ..
+            } => self.move_cursor(|review| review.move_cursor(-1)),
+            KeyEvent {
+                code: KeyCode::Char(']'),
+                ..
+            } => self.move_cursor(|review| review.move_to_file(1)),
+            KeyEvent {
+                code: KeyCode::Char('['),
+                ..
+            } => self.move_cursor(|review| review.move_to_file(-1)),
+            KeyEvent {
+                code: KeyCode::Char('z'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.toggle_fold(),
+            KeyEvent {
+                code: KeyCode::Char('f'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.show_files = !self.show_files,
+            KeyEvent {
+                code: KeyCode::Char('v'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.side_by_side = !self.side_by_side,
+            KeyEvent {
+                code: KeyCode::Char('e'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.edit_requested = true,
+            KeyEvent {
+                code: KeyCode::Char('l'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => self.select_lines(),
+            KeyEvent {
+                code: KeyCode::Char('s'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => {
+                if let Some(req) = self.requests.peek_mut() {
+                    req.split();
+                }
+            }
+            KeyEvent {
+                code: KeyCode::Char('x'),
+                modifiers: KeyModifiers::NONE,
+                ..
+            } => {
+                if let Some(req) = self.requests.peek() {
+                    req.expand();
+                }
+            }
+            KeyEvent {
+                code: KeyCode::Tab, ..
+            } => self.select_request(1),
+            KeyEvent {
+                code: KeyCode::BackTab,
+                ..
+            } => self.select_request(-1),
             KeyEvent {
                 code: KeyCode::Char('k'),
                 modifiers: KeyModifiers::NONE,
@@ -253,6 +639,191 @@ impl App {
         }
     }
 
+    /// edits the commit message: <Enter> saves, <Alt-Enter> adds a newline and <Esc> cancels
+    fn handle_message_input(&mut self, key_event: KeyEvent) {
+        let Some(input) = self.message_input.as_mut() else {
+            return;
+        };
+        match key_event.code {
+            KeyCode::Enter if key_event.modifiers.contains(KeyModifiers::ALT) => input.push('\n'),
+            KeyCode::Enter => {
+                let message = self.message_input.take().filter(|m| !m.trim().is_empty());
+                if let Some(req) = self.requests.peek_mut() {
+                    req.commit_message = message;
+                }
+            }
+            KeyCode::Esc => self.message_input = None,
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Char(c) => input.push(c),
+            _ => {}
+        }
+    }
+
+    /// starts picking out lines of the hunk under the cursor
+    fn select_lines(&mut self) {
+        let Some(req) = self.requests.peek() else {
+            return;
+        };
+        let Some(
+            id @ ItemId {
+                file,
+                hunk: Some(hunk),
+            },
+        ) = req.review.cursor()
+        else {
+            return;
+        };
+        self.line_selection =
+            LineSelection::new(id, &req.review.files()[file].patched.hunks()[hunk]);
+    }
+
+    /// picks out lines: <j>/<k> move, <space> toggles, <V> starts a range, <Enter>
+    /// accepts the selected lines and <Esc> cancels
+    async fn handle_line_selection(&mut self, key_event: KeyEvent) {
+        let (Some(selection), Some(req)) = (self.line_selection.as_mut(), self.requests.peek_mut())
+        else {
+            self.line_selection = None;
+            return;
+        };
+        let ItemId { file, hunk } = selection.id;
+        let Some(hunk) = hunk.filter(|_| req.review.cursor() == Some(selection.id)) else {
+            self.line_selection = None;
+            return;
+        };
+        let lines = &req.review.files()[file].patched.hunks()[hunk];
+        match key_event.code {
+            KeyCode::Char('j') | KeyCode::Down => selection.move_cursor(lines, true),
+            KeyCode::Char('k') | KeyCode::Up => selection.move_cursor(lines, false),
+            KeyCode::Char(' ') => selection.toggle(lines),
+            KeyCode::Char('V') => {
+                selection.anchor = match selection.anchor {
+                    Some(_) => None,
+                    None => Some(selection.cursor),
+                }
+            }
+            KeyCode::Enter => {
+                let keep = std::mem::take(&mut selection.selected);
+                self.line_selection = None;
+                // with nothing left to accept, the whole hunk is rejected
+                let status = match req.review.select_lines(file, hunk, &keep) {
+                    Ok(()) => {
+                        req.highlights[file] = highlight_file(&req.review.files()[file]);
+                        Status::Accepted
+                    }
+                    Err(_) => Status::Rejected,
+                };
+                info!("accepting selected lines as {status:?}");
+                self.handle_hunk_decision(status).await;
+            }
+            KeyCode::Esc => self.line_selection = None,
+            _ => {}
+        }
+    }
+
+    /// hands the hunk under the cursor to the reviewer's editor, accepting it as edited
+    async fn edit_hunk(&mut self, terminal: &mut DefaultTerminal) -> anyhow::Result<()> {
+        let Some(req) = self.requests.peek_mut() else {
+            return Ok(());
+        };
+        let Some(ItemId {
+            file,
+            hunk: Some(hunk),
+        }) = req.review.cursor()
+        else {
+            return Ok(());
+        };
+        // created afresh, readable only by us, and removed when dropped
+        let mut temp = tempfile::Builder::new()
+            .prefix("patchpal-")
+            .suffix(".diff")
+            .tempfile()?;
+        temp.write_all(
+            review::edit_template(&req.review.files()[file].patched.hunks()[hunk]).as_bytes(),
+        )?;
+        let path = temp.path();
+
+        ratatui::restore();
+        // the editor has the terminal to itself, without stalling the server's connections
+        let edited = tokio::task::block_in_place(|| run_editor(path))
+            .and_then(|_| Ok(std::fs::read_to_string(path)?));
+        *terminal = ratatui::init();
+
+        match edited.and_then(|edited| req.review.edit_hunk(file, hunk, &edited)) {
+            Ok(()) => {
+                req.highlights[file] = highlight_file(&req.review.files()[file]);
+                req.error = None;
+                self.handle_hunk_decision(Status::Accepted).await;
+            }
+            Err(e) => req.error = Some(format!("Couldn't edit the hunk: {e:#}")),
+        }
+        Ok(())
+    }
+
+    /// records a decision for the current hunk, responding once every hunk is decided
+    async fn handle_hunk_decision(&mut self, status: Status) {
+        let Some(req) = self.requests.peek_mut() else {
+            return;
+        };
+        req.review.decide(status);
+        req.unfold_cursor();
+        self.follow_cursor = true;
+        if req.review.is_done() {
+            let response = req.response();
+            self.handle_patch_response(response).await;
+        }
+    }
+
+    async fn decide_all_remaining(&mut self, status: Status) {
+        while let Some(mut req) = self.requests.pop() {
+            req.review.decide_rest(status);
+            let _ = req.response_chan.send(req.response()).await;
+        }
+    }
+
+    fn move_cursor(&mut self, step: impl FnOnce(&mut Review)) {
+        if let Some(req) = self.requests.peek_mut() {
+            step(&mut req.review);
+            req.unfold_cursor();
+            self.follow_cursor = true;
+        }
+    }
+
+    /// folds (or unfolds) the hunks of the file under the cursor, moving on to
+    /// another file's undecided items so nothing is decided without being seen
+    fn toggle_fold(&mut self) {
+        let Some(req) = self.requests.peek_mut() else {
+            return;
+        };
+        let Some(cursor) = req.review.cursor() else {
+            return;
+        };
+        if !req.folded.remove(&cursor.file) {
+            if !req.review.move_past_file(cursor.file) {
+                req.error =
+                    Some("Nothing is left to decide in other files, so this one stays open".into());
+                return;
+            }
+            req.folded.insert(cursor.file);
+            req.unfold_cursor();
+        }
+        self.follow_cursor = true;
+    }
+
+    /// reviews another patch in the queue, keeping the decisions made so far on this one
+    fn select_request(&mut self, offset: isize) {
+        self.requests.select(offset);
+        self.reset_view();
+    }
+
+    fn reset_view(&mut self) {
+        self.scroll_state.scroll_to_top();
+        self.follow_cursor = true;
+        self.message_input = None;
+        self.line_selection = None;
+    }
+
     async fn handle_patch_response(&mut self, response: PatchResponse) {
         info!("handling patch reponse: {:?}", response);
         let req = self
@@ -263,6 +834,7 @@ impl App {
             .send(response)
             .await
             .expect("should be able to respond");
+        self.reset_view();
     }
 
     fn exit(&mut self) {
@@ -272,16 +844,28 @@ impl App {
 
 impl Widget for &mut App {
     fn render(self, area: Rect, buf: &mut Buffer) {
-        let active = self.requests.peek();
+        self.requests.receive();
+        let pending = self.requests.pending.len();
+        let active = self.requests.pending.get(self.requests.selected);
 
         let title = match active {
             None => Line::from(" Patchpal (waiting..) ".bold()),
+            Some(_) if pending > 1 => Line::from(format!(" Patchpal ({pending} pending) ").bold()),
             Some(_) => Line::from(" Patchpal ".bold()),
         };
 
+        let progress = match active {
+            Some(patch) => {
+                let (position, total) = patch.review.progress();
+                format!(" ({position}/{total})")
+            }
+            None => String::new(),
+        };
+
         // (1/1) Stage this hunk [y,n,q,a,d,e,?]?
-        let instructions = Line::from(vec![
-            " Accept this patch ".into(),
+        let mut instructions = Line::from(vec![
+            progress.into(),
+            " Accept this hunk ".into(),
             "[".into(),
             // yes
             "y".light_green().bold(),
@@ -295,86 +879,480 @@ impl Widget for &mut App {
             // done
             "d".red().bold(),
             "one,".into(),
+            // split
+            "s".yellow().bold(),
+            "plit,".into(),
+            // edit
+            "e".yellow().bold(),
+            "dit,".into(),
+            // lines
+            "l".yellow().bold(),
+            "ines,".into(),
             // quit
             "q".blue().bold(),
             "uit".into(),
-            "] ".into(),
         ]);
+        if active.is_some_and(|r| r.commit) {
+            instructions.push_span(",");
+            instructions.push_span("m".yellow().bold());
+            instructions.push_span("essage");
+        }
+        if pending > 1 {
+            instructions.push_span(",");
+            instructions.push_span("tab".blue().bold());
+            instructions.push_span(" next patch");
+        }
+        instructions.push_span("] ");
+        if self.message_input.is_some() {
+            instructions = Line::from(vec![
+                " Commit message ".into(),
+                "[".into(),
+                "enter".yellow().bold(),
+                " save,".into(),
+                "alt-enter".yellow().bold(),
+                " newline,".into(),
+                "esc".blue().bold(),
+                " cancel] ".into(),
+            ]);
+        }
+        if self.line_selection.is_some() {
+            instructions = Line::from(vec![
+                " Select lines ".into(),
+                "[".into(),
+                "space".yellow().bold(),
+                " toggle,".into(),
+                "V".yellow().bold(),
+                " range,".into(),
+                "enter".light_green().bold(),
+                " accept selected,".into(),
+                "esc".blue().bold(),
+                " cancel] ".into(),
+            ]);
+        }
 
         let block = Block::new()
             .title(title.centered())
             .title_bottom(instructions.centered());
 
+        let mut diff_area = block.inner(area);
+        // the queue and file list only earn their space once there's something to choose between
+        let show_queue = pending > 1;
+        let show_files = self.show_files && active.is_some_and(|r| r.review.files().len() > 1);
+        if (show_queue || show_files) && diff_area.width >= SIDEBAR_WIDTH * 3 {
+            let [sidebar, rest] =
+                Layout::horizontal([Constraint::Length(SIDEBAR_WIDTH), Constraint::Fill(1)])
+                    .areas(diff_area);
+            diff_area = rest;
+
+            let (queue_area, files_area) = match (show_queue, show_files) {
+                (true, true) => {
+                    let queue_height = (pending as u16 * 2 + 1).min(sidebar.height / 2);
+                    let [queue_area, files_area] =
+                        Layout::vertical([Constraint::Length(queue_height), Constraint::Fill(1)])
+                            .areas(sidebar);
+                    (queue_area, files_area)
+                }
+                (true, false) => (sidebar, Rect::default()),
+                _ => (Rect::default(), sidebar),
+            };
+            if show_queue {
+                QueueWidget {
+                    pending: &self.requests.pending,
+                    selected: self.requests.selected,
+                }
+                .render(queue_area, buf);
+            }
+            if let Some(patch) = active.filter(|_| show_files) {
+                FilesWidget {
+                    review: &patch.review,
+                    folded: &patch.folded,
+                }
+                .render(files_area, buf);
+            }
+        }
+
         if let Some(patch) = active {
+            let commit_message = match &self.message_input {
+                Some(input) => Some(CommitMessage::Editing(input)),
+                None if patch.commit => Some(CommitMessage::Saved(patch.commit_message.as_deref())),
+                None => None,
+            };
             DiffWidget {
-                inner: &patch.patch_set,
+                review: &patch.review,
+                folded: &patch.folded,
+                highlights: &patch.highlights,
+                expanded: &patch.expanded,
+                error: patch.error.as_deref(),
                 metadata: patch.metadata.as_deref(),
+                client: patch.client.as_deref(),
+                commit_message,
+                side_by_side: self.side_by_side && diff_area.width >= MIN_SIDE_BY_SIDE_WIDTH,
+                line_selection: self.line_selection.as_ref(),
+                follow_cursor: self.follow_cursor,
             }
-            .render(block.inner(area), buf, &mut self.scroll_state);
+            .render(diff_area, buf, &mut self.scroll_state);
+            self.follow_cursor = false;
         }
 
         Paragraph::default().block(block).render(area, buf);
     }
 }
 
+const SIDEBAR_WIDTH: u16 = 36;
+/// narrower than this, side-by-side hunks fall back to unified ones
+const MIN_SIDE_BY_SIDE_WIDTH: u16 = 100;
+
+/// the sidebar listing every pending patch
+struct QueueWidget<'a> {
+    pending: &'a [PatchRequest],
+    selected: usize,
+}
+
+impl Widget for QueueWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let items: Vec<ListItem> = self
+            .pending
+            .iter()
+            .map(|req| {
+                let name = match &req.metadata {
+                    Some(metadata) => Line::from(metadata.clone()),
+                    None => Line::from("(no metadata)".dim()),
+                };
+                let files = req.review.files();
+                let added: usize = files.iter().map(|f| f.patched.added()).sum();
+                let removed: usize = files.iter().map(|f| f.patched.removed()).sum();
+
+                let mut details = Line::from(" ");
+                if let Some(repo) = &req.repo {
+                    details.push_span(repo.clone().blue());
+                    details.push_span(" ");
+                }
+                details.push_span(format!("{} file(s) ", files.len()).dim());
+                details.push_span(format!("+{added}").green());
+                details.push_span(" ");
+                details.push_span(format!("-{removed}").red());
+                details.push_span(format!(" {}", format_age(req.received.elapsed())).dim());
+                ListItem::new(vec![name, details])
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(
+                Block::new()
+                    .borders(Borders::RIGHT)
+                    .title(format!(" Queue ({}) ", self.pending.len())),
+            )
+            .highlight_symbol("▶ ")
+            .highlight_style(Style::new().yellow());
+        let mut state = ListState::default().with_selected(Some(self.selected));
+        StatefulWidget::render(list, area, buf, &mut state);
+    }
+}
+
+/// the sidebar listing the active patch's files
+struct FilesWidget<'a> {
+    review: &'a Review,
+    folded: &'a BTreeSet<usize>,
+}
+
+impl Widget for FilesWidget<'_> {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let items: Vec<ListItem> = self
+            .review
+            .files()
+            .iter()
+            .enumerate()
+            .map(|(idx, file)| {
+                let status = match file.change.kind() {
+                    Kind::Modified => "M".blue(),
+                    Kind::Added => "A".green(),
+                    Kind::Deleted => "D".red(),
+                    Kind::Renamed => "R".yellow(),
+                    Kind::Copied => "C".yellow(),
+                    Kind::TypeChanged => "T".yellow(),
+                };
+                let mut line = Line::from(vec![
+                    status,
+                    " ".into(),
+                    file.path().to_string().into(),
+                    " ".into(),
+                    format!("+{}", file.patched.added()).green(),
+                    " ".into(),
+                    format!("-{}", file.patched.removed()).red(),
+                ]);
+                if self.folded.contains(&idx) {
+                    line.push_span(" ▸".dim());
+                }
+                let decided = self
+                    .review
+                    .items_of(idx)
+                    .all(|id| self.review.decision(id).is_some());
+                if decided {
+                    line = line.dim();
+                }
+                ListItem::new(line)
+            })
+            .collect();
+
+        let list = List::new(items)
+            .block(
+                Block::new()
+                    .borders(Borders::RIGHT)
+                    .title(format!(" Files ({}) ", self.review.files().len()))
+                    .title_bottom(Line::from(vec![
+                        " [".blue().bold(),
+                        "/".into(),
+                        "]".blue().bold(),
+                        " file ".into(),
+                        "K".blue().bold(),
+                        "/".into(),
+                        "J".blue().bold(),
+                        " hunk ".into(),
+                        "z".blue().bold(),
+                        " fold ".into(),
+                    ])),
+            )
+            .highlight_symbol("▶ ")
+            .highlight_style(Style::new().yellow());
+        let selected = self.review.cursor().map(|c| c.file);
+        let mut state = ListState::default().with_selected(selected);
+        StatefulWidget::render(list, area, buf, &mut state);
+    }
+}
+
+/// e.g. "42s", "5m", "3h"
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        s if s < 60 => format!("{s}s"),
+        s if s < 60 * 60 => format!("{}m", s / 60),
+        s if s < 24 * 60 * 60 => format!("{}h", s / (60 * 60)),
+        s => format!("{}d", s / (24 * 60 * 60)),
+    }
+}
+
+enum CommitMessage<'a> {
+    Saved(Option<&'a str>),
+    Editing(&'a str),
+}
+
 struct DiffWidget<'a> {
-    inner: &'a PatchSet,
+    review: &'a Review,
+    /// files whose items are hidden
+    folded: &'a BTreeSet<usize>,
+    highlights: &'a [Vec<HunkHighlights>],
+    expanded: &'a BTreeMap<(usize, usize), Expansion>,
+    error: Option<&'a str>,
     metadata: Option<&'a str>,
+    client: Option<&'a str>,
+    /// only present if the client commits what's accepted
+    commit_message: Option<CommitMessage<'a>>,
+    side_by_side: bool,
+    line_selection: Option<&'a LineSelection>,
+    /// scroll so that the hunk under the cursor is visible
+    follow_cursor: bool,
 }
 
 impl StatefulWidget for DiffWidget<'_> {
     type State = ScrollViewState;
 
     fn render(self, area: Rect, buf: &mut Buffer, state: &mut Self::State) {
-        let metadata = match self.metadata {
-            Some(metadata) => {
-                Paragraph::new(Line::from(vec!["Metadata: ".blue(), metadata.into()]))
+        let mut header = Text::default();
+        let mut submitted = Line::default();
+        if let Some(metadata) = self.metadata {
+            submitted.push_span("Metadata: ".blue());
+            submitted.push_span(metadata);
+        }
+        if let Some(client) = self.client {
+            if self.metadata.is_some() {
+                submitted.push_span("  ");
             }
-            None => Paragraph::default(),
-        };
+            submitted.push_span("Client: ".blue());
+            submitted.push_span(client);
+        }
+        if !submitted.spans.is_empty() {
+            header.push_line(submitted);
+        }
+        match self.commit_message {
+            Some(CommitMessage::Saved(Some(message))) => {
+                header.push_line(Line::from("Commit message:".blue()));
+                for line in message.lines() {
+                    header.push_line(Line::from(line.to_string()));
+                }
+            }
+            Some(CommitMessage::Saved(None)) => {
+                header.push_line(Line::from(vec![
+                    "Commit message: ".blue(),
+                    "(from client template)".dim(),
+                ]));
+            }
+            Some(CommitMessage::Editing(input)) => {
+                header.push_line(Line::from("Commit message:".yellow()));
+                let mut lines: Vec<_> = input.split('\n').map(|l| l.to_string()).collect();
+                if let Some(last) = lines.last_mut() {
+                    last.push('▏');
+                }
+                for line in lines {
+                    header.push_line(Line::from(line.yellow()));
+                }
+            }
+            None => {}
+        }
+        if let Some(error) = self.error {
+            header.push_line(Line::from(error.to_string().red()));
+        }
+        let metadata = Paragraph::new(header);
 
-        let mut patch_offset_y =
-            area.top() + metadata.line_count(metadata.line_width() as u16) as u16;
-        let mut hunks_render_info = vec![];
-        for patch in self.inner.files() {
-            // TODO: print the file name too
-            let mut hunk_offset_y = 0u16;
-            for hunk in patch.hunks() {
+        // everything is laid out in the scroll view's own coordinates
+        let mut patch_offset_y = metadata.line_count(metadata.line_width() as u16) as u16;
+        let cursor = self.review.cursor();
+        let render_hunk = if self.side_by_side {
+            hunk_text_side_by_side
+        } else {
+            hunk_text
+        };
+        let mut rows = vec![];
+        for (file_idx, file) in self.review.files().iter().enumerate() {
+            let folded = self.folded.contains(&file_idx);
+            rows.push(Row::File {
+                file: file_idx,
+                line: file_heading(file, folded),
+            });
+            if folded {
+                continue;
+            }
+            if file.has_file_change() {
+                let id = ItemId {
+                    file: file_idx,
+                    hunk: None,
+                };
+                if file.is_pure_move() {
+                    // nothing to show beyond the summary, so skip the box around it
+                    let mut summary = file_change_summary(file);
+                    summary.push_span(" ");
+                    rows.push(Row::Item {
+                        id,
+                        title: summary,
+                        text: Text::default(),
+                        boxed: false,
+                    });
+                } else {
+                    rows.push(Row::Item {
+                        id,
+                        title: Line::default(),
+                        text: Text::from(file_change_summary(file)),
+                        boxed: true,
+                    });
+                }
+            }
+            if file.is_whole_file() {
+                continue;
+            }
+            let patch = &file.patched;
+            for (hunk_idx, hunk) in patch.hunks().iter().enumerate() {
+                let id = ItemId {
+                    file: file_idx,
+                    hunk: Some(hunk_idx),
+                };
                 let hunk_title = Line::from(vec![
                     " From:".into(),
                     format!(" {} ", patch.source_file).red().bold(),
                     "To:".into(),
                     format!(" {} ", patch.target_file).green().bold(),
                 ]);
-                let mut hunk_text = Text::from(vec![]);
-                for line in hunk.lines() {
-                    match line {
-                        l if l.is_added() => {
-                            hunk_text.lines.push(Line::from(l.value.clone().green()))
-                        }
-                        l if l.is_removed() => {
-                            hunk_text.lines.push(Line::from(l.value.clone().red()))
-                        }
-                        l if l.is_context() => {
-                            hunk_text.lines.push(Line::from(l.value.clone().dim()))
-                        }
-                        _ => unreachable!(),
+                rows.push(Row::Item {
+                    id,
+                    title: hunk_title,
+                    text: render_hunk(
+                        hunk,
+                        self.highlights.get(file_idx).and_then(|h| h.get(hunk_idx)),
+                        self.expanded.get(&(file_idx, hunk_idx)),
+                        self.line_selection.filter(|s| s.id == id),
+                        // inside the box's borders
+                        area.width.saturating_sub(3) as usize,
+                    ),
+                    boxed: true,
+                });
+            }
+        }
+
+        let mut rows_render_info = vec![];
+        for row in rows {
+            let (id, mut title, text, boxed) = match row {
+                Row::File { file, line } => {
+                    let row_area = Rect {
+                        x: 0,
+                        y: patch_offset_y,
+                        width: area.width - 1,
+                        height: 1,
+                    };
+                    patch_offset_y += row_area.height;
+                    // the cursor's item is hidden away in a folded file, so point at the file
+                    let has_cursor =
+                        self.folded.contains(&file) && cursor.is_some_and(|c| c.file == file);
+                    let mut row_paragraph = Paragraph::new(line);
+                    if has_cursor {
+                        row_paragraph = row_paragraph.yellow();
+                        follow(state, self.follow_cursor, row_area, area.height);
                     }
+                    rows_render_info.push((row_area, row_paragraph));
+                    continue;
                 }
+                Row::Item {
+                    id,
+                    title,
+                    text,
+                    boxed,
+                } => (id, title, text, boxed),
+            };
+            match self.review.decision(id) {
+                Some(Status::Accepted) => title.push_span("[accepted] ".light_green()),
+                Some(Status::Rejected) => title.push_span("[rejected] ".light_red()),
+                _ => {}
+            }
 
-                let hunk_area = Rect {
-                    x: area.left(),
-                    y: patch_offset_y + hunk_offset_y,
-                    width: area.width - 1,
-                    height: hunk_text.height() as u16,
+            if !boxed {
+                let item_area = Rect {
+                    x: 1,
+                    y: patch_offset_y,
+                    width: area.width - 2,
+                    height: 1,
                 };
-                hunk_offset_y += hunk_text.height() as u16;
+                patch_offset_y += item_area.height;
 
-                let hunk_paragraph =
-                    Paragraph::new(hunk_text).block(Block::bordered().title(hunk_title));
-                hunks_render_info.push((hunk_area, hunk_paragraph));
+                let mut row = Line::from(" ");
+                if cursor == Some(id) {
+                    row = Line::from("▶".yellow());
+                    follow(state, self.follow_cursor, item_area, area.height);
+                }
+                row.push_span(" ");
+                title.spans.into_iter().for_each(|span| row.push_span(span));
+                let mut item_paragraph = Paragraph::new(row);
+                if cursor != Some(id) && self.review.decision(id).is_some() {
+                    item_paragraph = item_paragraph.dim();
+                }
+                rows_render_info.push((item_area, item_paragraph));
+                continue;
+            }
+
+            let item_area = Rect {
+                x: 0,
+                y: patch_offset_y,
+                width: area.width - 1,
+                // account for the border
+                height: text.height() as u16 + 2,
+            };
+            patch_offset_y += item_area.height;
+
+            let mut item_block = Block::bordered().title(title);
+            if cursor == Some(id) {
+                item_block = item_block.yellow();
+                follow(state, self.follow_cursor, item_area, area.height);
+            } else if self.review.decision(id).is_some() {
+                item_block = item_block.dim();
             }
-            patch_offset_y += hunk_offset_y;
+
+            let item_paragraph = Paragraph::new(text).block(item_block);
+            rows_render_info.push((item_area, item_paragraph));
         }
         let mut scroll_view = ScrollView::new(Size::new(area.width, patch_offset_y))
             .scrollbars_visibility(ScrollbarVisibility::Never);
@@ -384,11 +1362,472 @@ impl StatefulWidget for DiffWidget<'_> {
                 .clone()
                 .wrap(Wrap { trim: true })
                 .block(Block::new().padding(Padding::horizontal(1))),
-            area,
+            Rect::new(0, 0, area.width, area.height),
         );
-        for (hunk_area, hunk_paragraph) in hunks_render_info {
-            scroll_view.render_widget(hunk_paragraph, hunk_area);
+        for (row_area, row_paragraph) in rows_render_info {
+            scroll_view.render_widget(row_paragraph, row_area);
         }
         scroll_view.render(area, buf, state);
     }
 }
+
+/// a row of the diff view
+enum Row<'a> {
+    /// a file's name and stats, heading its items
+    File { file: usize, line: Line<'a> },
+    /// something to decide on, boxed with its title on the border unless it fits on one line
+    Item {
+        id: ItemId,
+        title: Line<'a>,
+        text: Text<'a>,
+        boxed: bool,
+    },
+}
+
+/// runs `$VISUAL` (or `$EDITOR`, or vi) on the file at `path`, through the shell so
+/// that editors with arguments work
+fn run_editor(path: &Path) -> anyhow::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or("vi".to_string());
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("couldn't run {editor}"))?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
+    Ok(())
+}
+
+/// scrolls `row_area` into view if it's hidden and the cursor should be followed
+fn follow(state: &mut ScrollViewState, follow_cursor: bool, row_area: Rect, height: u16) {
+    let offset = state.offset().y;
+    let hidden = row_area.top() < offset || row_area.bottom() > offset.saturating_add(height);
+    if follow_cursor && hidden {
+        state.set_offset(Position::new(0, row_area.top()));
+    }
+}
+
+/// the hunk's lines, one under the other with their old and new line numbers, filling `width`
+fn hunk_text(
+    hunk: &Hunk,
+    highlights: Option<&HunkHighlights>,
+    expansion: Option<&Expansion>,
+    selection: Option<&LineSelection>,
+    width: usize,
+) -> Text<'static> {
+    let digits = gutter_digits(hunk, expansion);
+    let (before, after) = expanded_lines(hunk, expansion);
+    let expanded_row = |(old, new, line): (usize, usize, &str)| {
+        let mut row = Line::from(vec![gutter(Some(old), digits), gutter(Some(new), digits)]);
+        let line = Line::from(line.to_string().dim());
+        row.spans
+            .extend(fit(line, width.saturating_sub(2 * (digits + 1))).spans);
+        row
+    };
+    let mut text = Text::from(hunk_header(hunk));
+    text.lines.extend(before.into_iter().map(expanded_row));
+    for (idx, line) in hunk.lines().iter().enumerate() {
+        let mut row = Line::from(vec![
+            gutter(line.source_line_no, digits),
+            gutter(line.target_line_no, digits),
+        ]);
+        if let Some(style) = selection.and_then(|s| s.gutter_style(idx)) {
+            for span in &mut row.spans {
+                span.style = span.style.patch(style);
+            }
+        }
+        let styled = selected_line(line, idx, highlights, selection);
+        row.spans
+            .extend(fit(styled, width.saturating_sub(2 * (digits + 1))).spans);
+        text.lines.push(row);
+    }
+    text.lines.extend(after.into_iter().map(expanded_row));
+    text
+}
+
+/// the hunk with its old lines on the left and new lines on the right, each
+/// replaced line beside its replacement
+fn hunk_text_side_by_side(
+    hunk: &Hunk,
+    highlights: Option<&HunkHighlights>,
+    expansion: Option<&Expansion>,
+    selection: Option<&LineSelection>,
+    width: usize,
+) -> Text<'static> {
+    let lines = hunk.lines();
+    // a "\ No newline at end of file" marker stays with the line before it
+    let run = |mut idx: usize, matches: fn(&unidiff::Line) -> bool| {
+        let start = idx;
+        while idx < lines.len() && (matches(&lines[idx]) || (idx > start && is_marker(&lines[idx])))
+        {
+            idx += 1;
+        }
+        start..idx
+    };
+
+    let mut rows: Vec<(Option<usize>, Option<usize>)> = vec![];
+    let mut idx = 0;
+    while idx < lines.len() {
+        let line = &lines[idx];
+        if line.is_removed() || line.is_added() {
+            let removed = run(idx, unidiff::Line::is_removed);
+            let added = run(removed.end, unidiff::Line::is_added);
+            idx = added.end;
+            for row in 0..removed.len().max(added.len()) {
+                rows.push((removed.clone().nth(row), added.clone().nth(row)));
+            }
+        } else {
+            rows.push((Some(idx), Some(idx)));
+            idx += 1;
+        }
+    }
+
+    let digits = gutter_digits(hunk, expansion);
+    let half = width.saturating_sub(1) / 2;
+    let side = |idx: Option<usize>, line_no: fn(&unidiff::Line) -> Option<usize>| {
+        let Some(idx) = idx else {
+            return vec![" ".repeat(half).into()];
+        };
+        let line = &lines[idx];
+        let mut gutter = gutter(line_no(line), digits);
+        if let Some(style) = selection.and_then(|s| s.gutter_style(idx)) {
+            gutter = gutter.patch_style(style);
+        }
+        let mut spans = vec![gutter];
+        let styled = selected_line(line, idx, highlights, selection);
+        spans.extend(fit(styled, half.saturating_sub(digits + 1)).spans);
+        spans
+    };
+    let (before, after) = expanded_lines(hunk, expansion);
+    let expanded_row = |(old, new, line): (usize, usize, &str)| {
+        let side = |line_no| {
+            let line = Line::from(line.to_string().dim());
+            let mut spans = vec![gutter(Some(line_no), digits)];
+            spans.extend(fit(line, half.saturating_sub(digits + 1)).spans);
+            spans
+        };
+        let mut row = Line::from(side(old));
+        row.push_span("│".dim());
+        row.spans.extend(side(new));
+        row
+    };
+
+    let mut text = Text::from(hunk_header(hunk));
+    text.lines.extend(before.into_iter().map(expanded_row));
+    for (old, new) in rows {
+        let mut row = Line::from(side(old, |l| l.source_line_no));
+        row.push_span("│".dim());
+        row.spans.extend(side(new, |l| l.target_line_no));
+        text.lines.push(row);
+    }
+    text.lines.extend(after.into_iter().map(expanded_row));
+    text
+}
+
+/// the first line of the hunk on its new side, and how many lines it has there
+/// the expanded lines before and after the hunk, as (old line number, new line number, line)
+fn expanded_lines<'e>(
+    hunk: &Hunk,
+    expansion: Option<&'e Expansion>,
+) -> (Numbered<'e>, Numbered<'e>) {
+    let Some(expansion) = expansion else {
+        return (vec![], vec![]);
+    };
+    let (first, len) = new_side(hunk);
+    // lines outside the hunk are the same on both sides, just shifted
+    let old_first = hunk.source_start + usize::from(hunk.source_length == 0);
+    let numbered = |old_first: usize, new_first: usize, lines: &'e [String]| {
+        (old_first..)
+            .zip(new_first..)
+            .zip(lines)
+            .map(|((old, new), line)| (old, new, line.as_str()))
+            .collect()
+    };
+    let before_len = expansion.before.len();
+    let before = numbered(
+        old_first - before_len,
+        first - before_len,
+        &expansion.before,
+    );
+    let after = numbered(
+        old_first + hunk.source_length,
+        first + len,
+        &expansion.after,
+    );
+    (before, after)
+}
+
+/// lines with their old and new line numbers
+type Numbered<'a> = Vec<(usize, usize, &'a str)>;
+
+/// the `@@ -1,3 +1,4 @@` line, followed by the function or section the hunk is in
+fn hunk_header(hunk: &Hunk) -> Line<'static> {
+    let mut header = Line::from(
+        format!(
+            "@@ -{},{} +{},{} @@",
+            hunk.source_start, hunk.source_length, hunk.target_start, hunk.target_length
+        )
+        .cyan(),
+    );
+    if !hunk.section_header.is_empty() {
+        header.push_span(" ");
+        header.push_span(hunk.section_header.clone().italic());
+    }
+    header
+}
+
+/// how wide the hunk's line numbers get
+fn gutter_digits(hunk: &Hunk, expansion: Option<&Expansion>) -> usize {
+    let last = (hunk.source_start + hunk.source_length).max(hunk.target_start + hunk.target_length)
+        + expansion.map_or(0, |e| e.after.len());
+    last.max(1).ilog10() as usize + 1
+}
+
+/// a line number, right-aligned to `digits`, or blanks for a line that isn't on that side
+fn gutter(line_no: Option<usize>, digits: usize) -> Span<'static> {
+    match line_no {
+        Some(line_no) => format!("{line_no:>digits$} ").dim(),
+        None => " ".repeat(digits + 1).into(),
+    }
+}
+
+/// e.g. "\ No newline at end of file"
+fn is_marker(line: &unidiff::Line) -> bool {
+    !line.is_added() && !line.is_removed() && !line.is_context()
+}
+
+/// the hunk's `idx`th line on a green/red background, in syntax colors when
+/// `highlights` has them and with the words that changed picked out
+fn styled_line(
+    line: &unidiff::Line,
+    idx: usize,
+    highlights: Option<&HunkHighlights>,
+) -> Line<'static> {
+    let highlighted = highlights.and_then(|h| h.syntax.get(idx)).cloned();
+    let words = highlights
+        .and_then(|h| h.changed.get(idx))
+        .map_or(&[][..], Vec::as_slice);
+    match line {
+        l if l.is_added() => emphasize(
+            highlighted.unwrap_or_else(|| Line::from(l.value.clone().green())),
+            words,
+            ADDED_WORDS_BG,
+        )
+        .bg(ADDED_BG),
+        l if l.is_removed() => emphasize(
+            highlighted.unwrap_or_else(|| Line::from(l.value.clone().red())),
+            words,
+            REMOVED_WORDS_BG,
+        )
+        .bg(REMOVED_BG),
+        l if l.is_context() => highlighted.unwrap_or_else(|| Line::from(l.value.clone().dim())),
+        l => Line::from(l.to_string().dim().italic()),
+    }
+}
+
+/// the line as [`styled_line`] shows it, unless it's been left out of a selection
+fn selected_line(
+    line: &unidiff::Line,
+    idx: usize,
+    highlights: Option<&HunkHighlights>,
+    selection: Option<&LineSelection>,
+) -> Line<'static> {
+    if selection.is_some_and(|s| s.is_left_out(idx)) {
+        return Line::from(line.value.clone().dim().crossed_out());
+    }
+    styled_line(line, idx, highlights)
+}
+
+fn is_change(line: &unidiff::Line) -> bool {
+    line.is_added() || line.is_removed()
+}
+
+/// cuts the line off at `width` columns, or pads it out to them, keeping the
+/// line's style on each span
+fn fit(line: Line<'static>, width: usize) -> Line<'static> {
+    let mut fitted = Line::default();
+    let mut remaining = width;
+    for span in line.spans {
+        let style = line.style.patch(span.style);
+        let mut content = String::new();
+        for c in span.content.chars() {
+            let c_width = c.width().unwrap_or(0);
+            if c_width > remaining {
+                remaining = 0;
+                break;
+            }
+            remaining -= c_width;
+            content.push(c);
+        }
+        fitted.push_span(Span::styled(content, style));
+        if remaining == 0 {
+            break;
+        }
+    }
+    fitted.push_span(Span::styled(" ".repeat(remaining), line.style));
+    fitted
+}
+
+/// splits the line's spans so that the byte `ranges` of it get the background `bg`
+fn emphasize(line: Line<'static>, ranges: &[Range<usize>], bg: Color) -> Line<'static> {
+    if ranges.is_empty() {
+        return line;
+    }
+    let mut emphasized = Line::default().style(line.style);
+    let mut offset = 0;
+    for span in line.spans {
+        let end = offset + span.content.len();
+        let mut cuts: Vec<usize> = ranges
+            .iter()
+            .flat_map(|range| [range.start, range.end])
+            .filter(|&cut| offset < cut && cut < end)
+            .collect();
+        cuts.push(end);
+        let mut start = offset;
+        for cut in cuts {
+            let mut style = span.style;
+            if ranges.iter().any(|range| range.contains(&start)) {
+                style = style.bg(bg);
+            }
+            emphasized.push_span(Span::styled(
+                span.content[start - offset..cut - offset].to_string(),
+                style,
+            ));
+            start = cut;
+        }
+        offset = end;
+    }
+    emphasized
+}
+
+/// the file's path and line counts, e.g. "▾ src/main.rs +3 -1"
+fn file_heading(file: &FileDiff, folded: bool) -> Line<'static> {
+    let mut line = Line::from(vec![
+        if folded { "▸ " } else { "▾ " }.into(),
+        file.path().to_string().bold(),
+        " ".into(),
+        format!("+{}", file.patched.added()).green(),
+        " ".into(),
+        format!("-{}", file.patched.removed()).red(),
+    ]);
+    if folded {
+        line.push_span(" (folded)".dim());
+    }
+    line
+}
+
+/// a one-line description of a file's file-level changes, e.g. "renamed a.rs → b.rs"
+fn file_change_summary(file: &FileDiff) -> Line<'static> {
+    let change = &file.change;
+    let mut summary = match change.kind() {
+        Kind::Added => Line::from(vec![
+            "added ".green().bold(),
+            change.new_path.clone().into(),
+        ]),
+        Kind::Deleted => Line::from(vec![
+            "deleted ".red().bold(),
+            change.old_path.clone().into(),
+            format!(" ({} lines)", file.patched.removed()).dim(),
+        ]),
+        Kind::Renamed | Kind::Copied => {
+            let verb = if change.kind() == Kind::Renamed {
+                "renamed "
+            } else {
+                "copied "
+            };
+            let mut line = Line::from(vec![
+                verb.yellow().bold(),
+                format!("{} → {}", change.old_path, change.new_path).into(),
+            ]);
+            if let Some(similarity) = file.similarity() {
+                line.push_span(format!(" ({similarity}% similar)").dim());
+            }
+            line
+        }
+        Kind::TypeChanged => Line::from(vec![
+            "type changed ".yellow().bold(),
+            change.new_path.clone().into(),
+        ]),
+        Kind::Modified => Line::from(vec![
+            "modified ".blue().bold(),
+            change.new_path.clone().into(),
+        ]),
+    };
+    if file.mode_changed() {
+        summary.push_span(format!(" (mode {:o} → {:o})", change.old_mode, change.new_mode).dim());
+    }
+    if change.binary {
+        summary.push_span(" [binary]".magenta());
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    fn requests(names: &[&str]) -> (Requests, Sender<PatchRequest>) {
+        let (tx, receiver) = mpsc::channel(8);
+        let mut requests = Requests {
+            pending: vec![],
+            selected: 0,
+            receiver,
+        };
+        for name in names {
+            tx.try_send(request(name)).unwrap();
+        }
+        requests.receive();
+        (requests, tx)
+    }
+
+    fn request(name: &str) -> PatchRequest {
+        let patch = Patch {
+            metadata: Some(name.to_string()),
+            ..Default::default()
+        };
+        PatchRequest::try_from((patch, mpsc::channel(1).0)).unwrap()
+    }
+
+    fn selected(requests: &mut Requests) -> Option<String> {
+        requests.peek().and_then(|req| req.metadata.clone())
+    }
+
+    #[test]
+    fn selection_wraps_around() {
+        let (mut requests, _tx) = requests(&["a", "b", "c"]);
+        requests.select(-1);
+        assert_eq!(selected(&mut requests).as_deref(), Some("c"));
+        requests.select(1);
+        assert_eq!(selected(&mut requests).as_deref(), Some("a"));
+        requests.select(5);
+        assert_eq!(selected(&mut requests).as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn popping_removes_the_selected_request() {
+        let (mut requests, tx) = requests(&["a", "b", "c"]);
+        requests.select(1);
+        let popped = requests.pop().unwrap();
+        assert_eq!(popped.metadata.as_deref(), Some("b"));
+        assert_eq!(selected(&mut requests).as_deref(), Some("c"));
+
+        // arriving patches queue up behind the one being reviewed
+        tx.try_send(request("d")).unwrap();
+        assert_eq!(selected(&mut requests).as_deref(), Some("c"));
+        requests.select(1);
+        assert_eq!(requests.pop().unwrap().metadata.as_deref(), Some("d"));
+        // popping the last one selects the one before it
+        assert_eq!(selected(&mut requests).as_deref(), Some("c"));
+        assert_eq!(requests.pop().unwrap().metadata.as_deref(), Some("c"));
+        assert_eq!(requests.pop().unwrap().metadata.as_deref(), Some("a"));
+        assert!(requests.pop().is_none());
+        assert!(selected(&mut requests).is_none());
+    }
+}